@@ -0,0 +1,107 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use prometheus_remote_write::{Label, MetricName, Sample, TimeSeries, WriteRequest, LABEL_NAME};
+
+fn single_sample() -> WriteRequest {
+    WriteRequest {
+        timeseries: vec![TimeSeries {
+            labels: vec![
+                Label {
+                    name: "job".to_string(),
+                    value: "prom-write".to_string(),
+                },
+                Label {
+                    name: "instance".to_string(),
+                    value: "localhost:9090".to_string(),
+                },
+                Label {
+                    name: LABEL_NAME.to_string(),
+                    value: "http_requests_total".to_string(),
+                },
+            ],
+            samples: vec![Sample {
+                value: 1.0,
+                timestamp: 1_700_000_000_000,
+            }],
+            exemplars: vec![],
+        }],
+        metadata: vec![],
+    }
+}
+
+fn many_series() -> WriteRequest {
+    let name = MetricName::new("http_requests_total").unwrap();
+    WriteRequest {
+        timeseries: (0..1000)
+            .map(|index| {
+                TimeSeries::new(
+                    name.clone(),
+                    vec![Label {
+                        name: "instance".to_string(),
+                        value: format!("host-{index}"),
+                    }],
+                    (0..10)
+                        .rev()
+                        .map(|timestamp| Sample {
+                            value: index as f64,
+                            timestamp,
+                        })
+                        .collect(),
+                )
+            })
+            .collect(),
+        metadata: vec![],
+    }
+}
+
+fn text_input() -> String {
+    let mut text = String::new();
+    for timestamp in 0..100 {
+        for index in 0..100 {
+            text.push_str(&format!(
+                "http_requests_total{{instance=\"host-{index}\",job=\"x\"}} {index} {timestamp}\n"
+            ));
+        }
+    }
+    text
+}
+
+fn encode(c: &mut Criterion) {
+    let single = single_sample();
+    c.bench_function("encode_proto3/single_sample", |b| {
+        b.iter_batched(
+            || single.clone(),
+            |req| black_box(req.encode_proto3()),
+            BatchSize::SmallInput,
+        )
+    });
+    c.bench_function("encode_compressed/single_sample", |b| {
+        b.iter_batched(
+            || single.clone(),
+            |req| black_box(req.encode_compressed().unwrap()),
+            BatchSize::SmallInput,
+        )
+    });
+
+    let many = many_series();
+    c.bench_function("encode_compressed/1000_series", |b| {
+        b.iter_batched(
+            || many.clone(),
+            |req| black_box(req.encode_compressed().unwrap()),
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+fn parse(c: &mut Criterion) {
+    let text = text_input();
+    c.bench_function("from_text_format/10000_samples", |b| {
+        b.iter_batched(
+            || text.clone(),
+            |text| black_box(WriteRequest::from_text_format(text).unwrap()),
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+criterion_group!(benches, encode, parse);
+criterion_main!(benches);
@@ -6,6 +6,39 @@ pub const CONTENT_TYPE: &str = "application/x-protobuf";
 pub const HEADER_NAME_REMOTE_WRITE_VERSION: &str = "X-Prometheus-Remote-Write-Version";
 pub const REMOTE_WRITE_VERSION_01: &str = "0.1.0";
 
+/// The default histogram buckets used by the Prometheus client libraries.
+pub const DEFAULT_HISTOGRAM_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Protobuf field numbers of the remote write messages.
+///
+/// Useful for tooling that inspects encoded payloads without decoding them
+/// into the types of this crate. See also [`WriteRequest::PROTO_DESCRIPTOR`].
+pub mod field_tags {
+    pub const WRITE_REQUEST_TIMESERIES: u32 = 1;
+    pub const WRITE_REQUEST_METADATA: u32 = 3;
+
+    pub const TIME_SERIES_LABELS: u32 = 1;
+    pub const TIME_SERIES_SAMPLES: u32 = 2;
+    pub const TIME_SERIES_EXEMPLARS: u32 = 3;
+
+    pub const LABEL_NAME: u32 = 1;
+    pub const LABEL_VALUE: u32 = 2;
+
+    pub const SAMPLE_VALUE: u32 = 1;
+    pub const SAMPLE_TIMESTAMP: u32 = 2;
+
+    pub const EXEMPLAR_LABELS: u32 = 1;
+    pub const EXEMPLAR_VALUE: u32 = 2;
+    pub const EXEMPLAR_TIMESTAMP: u32 = 3;
+
+    pub const METRIC_METADATA_TYPE: u32 = 1;
+    pub const METRIC_METADATA_FAMILY_NAME: u32 = 2;
+    pub const METRIC_METADATA_HELP: u32 = 4;
+    pub const METRIC_METADATA_UNIT: u32 = 5;
+}
+
 /// A write request.
 ///
 /// .proto:
@@ -15,19 +48,31 @@ pub const REMOTE_WRITE_VERSION_01: &str = "0.1.0";
 ///   // Cortex uses this field to determine the source of the write request.
 ///   // We reserve it to avoid any compatibility issues.
 ///   reserved  2;
-
-///   // Prometheus uses this field to send metadata, but this is
-///   // omitted from v1 of the spec as it is experimental.
-///   reserved  3;
+///
+///   // Prometheus uses this field to send metadata. It is omitted from v1
+///   // of the spec as it is experimental, but widely accepted by receivers.
+///   repeated MetricMetadata metadata = 3;
 /// }
 /// ```
 #[derive(prost::Message, Clone, PartialEq)]
 pub struct WriteRequest {
     #[prost(message, repeated, tag = "1")]
     pub timeseries: Vec<TimeSeries>,
+    #[prost(message, repeated, tag = "3")]
+    pub metadata: Vec<MetricMetadata>,
 }
 
 impl WriteRequest {
+    /// Serialized `google.protobuf.FileDescriptorSet` describing the messages
+    /// of this crate.
+    ///
+    /// Contains a single `remote_write.proto` file in the `prometheus`
+    /// package, with the same message, field and enum names as the upstream
+    /// Prometheus definitions. Can be loaded by reflection libraries such as
+    /// `prost-reflect`, or passed to `protoc --decode` via
+    /// `--descriptor_set_in`.
+    pub const PROTO_DESCRIPTOR: &'static [u8] = include_bytes!("remote_write.desc");
+
     /// Prepare the write request for sending.
     ///
     /// Ensures that the request conforms to the specification.
@@ -43,229 +88,3678 @@ impl WriteRequest {
         self
     }
 
-    /// Encode this write request as a protobuf message.
+    /// Merge series with identical label sets.
     ///
-    /// NOTE: The API requires snappy compression, not a raw protobuf message.
-    pub fn encode_proto3(self) -> Vec<u8> {
-        prost::Message::encode_to_vec(&self.sorted())
+    /// Samples and exemplars of merged series are concatenated, and all
+    /// series are sorted as with [`Self::sort`]. If several samples or
+    /// several exemplars share a timestamp, the one from the series added
+    /// last is kept. Series keep the position of their first occurrence.
+    pub fn normalize(&mut self) {
+        // The sorts are stable, so the last of several items with the same
+        // timestamp is the one added last.
+        fn keep_last_per_timestamp<T>(items: &mut Vec<T>, timestamp: impl Fn(&T) -> i64) {
+            let mut kept: Vec<T> = Vec::with_capacity(items.len());
+            for item in items.drain(..) {
+                match kept.last_mut() {
+                    Some(last) if timestamp(last) == timestamp(&item) => *last = item,
+                    _ => kept.push(item),
+                }
+            }
+            *items = kept;
+        }
+
+        let mut index = std::collections::HashMap::<LabelSet, usize>::new();
+        let mut merged = Vec::<TimeSeries>::with_capacity(self.timeseries.len());
+        for series in self.timeseries.drain(..) {
+            match index.entry(series.label_set()) {
+                std::collections::hash_map::Entry::Occupied(entry) => {
+                    let target = &mut merged[*entry.get()];
+                    target.samples.extend(series.samples);
+                    target.exemplars.extend(series.exemplars);
+                }
+                std::collections::hash_map::Entry::Vacant(entry) => {
+                    entry.insert(merged.len());
+                    merged.push(series);
+                }
+            }
+        }
+
+        for series in &mut merged {
+            series.sort_labels_and_samples();
+            keep_last_per_timestamp(&mut series.samples, |s| s.timestamp);
+            keep_last_per_timestamp(&mut series.exemplars, |e| e.timestamp);
+        }
+        self.timeseries = merged;
     }
 
-    /// Encode this write request as a snappy-compressed protobuf message.
-    #[cfg(feature = "compression")]
-    pub fn encode_compressed(self) -> Result<Vec<u8>, snap::Error> {
-        snap::raw::Encoder::new().compress_vec(&self.encode_proto3())
+    /// Regroup series by a subset of their labels.
+    ///
+    /// Only the metric name and the labels named in `label_names` are kept,
+    /// and series that end up with identical labels are merged as with
+    /// [`Self::normalize`]. Sample values are not aggregated: if merged
+    /// series have samples at the same timestamp, the sample of the series
+    /// that comes last in the request is kept.
+    ///
+    /// ```
+    /// # use prometheus_remote_write::WriteRequest;
+    /// let labels = |pod: &str| vec![
+    ///     ("node".to_string(), "n1".to_string()),
+    ///     ("pod".to_string(), pod.to_string()),
+    /// ];
+    /// let mut req = WriteRequest::from_samples(vec![
+    ///     ("up".to_string(), labels("a"), 1.0, 1000),
+    ///     ("up".to_string(), labels("b"), 1.0, 2000),
+    /// ]);
+    /// req.group_by(&["node"]);
+    /// assert_eq!(req.timeseries.len(), 1);
+    /// assert_eq!(req.timeseries[0].samples.len(), 2);
+    /// ```
+    pub fn group_by(&mut self, label_names: &[&str]) {
+        for series in &mut self.timeseries {
+            series
+                .labels
+                .retain(|l| l.name == LABEL_NAME || label_names.contains(&l.name.as_str()));
+        }
+        self.normalize();
     }
 
-    /// Parse metrics from the Prometheus text format, and convert them into a
-    /// [`WriteRequest`].
-    #[cfg(feature = "parse")]
-    pub fn from_text_format(
-        text: String,
-    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
-        fn samples_to_timeseries(
-            samples: Vec<prometheus_parse::Sample>,
-        ) -> Result<Vec<TimeSeries>, Box<dyn std::error::Error + Send + Sync>> {
-            let mut all_series = std::collections::HashMap::<String, TimeSeries>::new();
+    /// Rename the metric `from` to `to`.
+    ///
+    /// Updates the `__name__` label of all matching series, and the family
+    /// name of matching metadata. The new name is not validated.
+    ///
+    /// If a series of `to` already exists with the same labels, the request
+    /// now contains duplicate series. Call [`Self::normalize`] afterwards to
+    /// merge them.
+    pub fn rename_metric(&mut self, from: &str, to: &str) {
+        for series in &mut self.timeseries {
+            for label in &mut series.labels {
+                if label.name == LABEL_NAME && label.value == from {
+                    label.value = to.to_string();
+                }
+            }
+        }
+        for metadata in &mut self.metadata {
+            if metadata.metric_family_name == from {
+                metadata.metric_family_name = to.to_string();
+            }
+        }
+    }
 
-            for sample in &samples {
-                let mut labels = sample
-                    .labels
-                    .iter()
-                    .map(|(k, v)| (k.as_str(), v.as_str()))
-                    .collect::<Vec<_>>();
+    /// Prefix all metric names with `prefix`, for example an application
+    /// name like `myapp_`.
+    ///
+    /// Every name is prefixed, including names that already start with
+    /// `prefix`: `jobs` and `myapp_jobs` become `myapp_jobs` and
+    /// `myapp_myapp_jobs`.
+    pub fn prefix_metric_names(&mut self, prefix: &str) {
+        for series in &mut self.timeseries {
+            for label in &mut series.labels {
+                if label.name == LABEL_NAME {
+                    label.value.insert_str(0, prefix);
+                }
+            }
+        }
+        for metadata in &mut self.metadata {
+            metadata.metric_family_name.insert_str(0, prefix);
+        }
+    }
+
+    /// Make metric and label names valid for remote write 1.0 receivers.
+    ///
+    /// Remote write 2.0 allows arbitrary UTF-8 names. This replaces
+    /// characters outside the legacy charset (`[a-zA-Z_:][a-zA-Z0-9_:]*` for
+    /// metric names, `[a-zA-Z_][a-zA-Z0-9_]*` for label names) with `_`,
+    /// and prefixes names starting with a digit with `_`. Metadata family
+    /// names are sanitized like metric names.
+    ///
+    /// Distinct names can map to the same sanitized name, for example
+    /// `a.b` and `a_b`. Use [`Self::validate`] to detect duplicate labels.
+    pub fn sanitize_names_legacy(&mut self) {
+        for series in &mut self.timeseries {
+            for label in &mut series.labels {
+                if label.name == LABEL_NAME {
+                    sanitize_legacy_name(&mut label.value, true);
+                } else {
+                    sanitize_legacy_name(&mut label.name, false);
+                }
+            }
+        }
+        for metadata in &mut self.metadata {
+            sanitize_legacy_name(&mut metadata.metric_family_name, true);
+        }
+    }
 
-                labels.push((LABEL_NAME, sample.metric.as_str()));
+    /// Add a sample to the series with the given labels, creating the
+    /// series if it does not exist yet.
+    ///
+    /// Series are matched by their [`LabelSet`], so the order of `labels`
+    /// does not matter. Finding the series is a linear search, see
+    /// [`Self::from_samples`] for building large requests.
+    pub fn push_sample(&mut self, labels: Vec<Label>, sample: Sample) {
+        let set = LabelSet::new(labels);
+        match self.timeseries.iter_mut().find(|s| set.matches(&s.labels)) {
+            Some(series) => series.samples.push(sample),
+            None => self.timeseries.push(TimeSeries {
+                labels: set.into(),
+                samples: vec![sample],
+                exemplars: vec![],
+            }),
+        }
+    }
 
-                labels.sort_by(|a, b| a.0.cmp(b.0));
+    /// Add a series to the request.
+    ///
+    /// Returns an error, without adding the series, if it has no `__name__`
+    /// label or no samples.
+    pub fn append_series(
+        &mut self,
+        series: TimeSeries,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let Some(name) = series.labels.iter().find(|l| l.name == LABEL_NAME) else {
+            return Err(format!("series is missing metric name label '{LABEL_NAME}'").into());
+        };
+        if series.samples.is_empty() {
+            return Err(format!("series '{}' has no samples", name.value).into());
+        }
+        self.timeseries.push(series);
+        Ok(())
+    }
 
-                let mut ident = sample.metric.clone();
-                ident.push_str("_$$_");
-                for (k, v) in &labels {
-                    ident.push_str(k);
-                    ident.push('=');
-                    ident.push_str(v);
+    /// Check that the request conforms to the specification.
+    ///
+    /// Every series must have a valid metric name, valid and unique label
+    /// names, at least one sample, and labels and samples must be sorted.
+    /// The returned error lists all problems, one per line.
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut problems = Vec::new();
+        for (index, series) in self.timeseries.iter().enumerate() {
+            match series.labels.iter().find(|l| l.name == LABEL_NAME) {
+                Some(name) => {
+                    if let Err(err) = MetricName::new(&name.value) {
+                        problems.push(format!("series {index}: {err}"));
+                    }
                 }
+                None => problems.push(format!(
+                    "series {index}: missing metric name label '{LABEL_NAME}'"
+                )),
+            }
 
-                let series = all_series.entry(ident).or_insert_with(|| {
-                    let labels = labels
-                        .iter()
-                        .map(|(k, v)| Label {
-                            name: k.to_string(),
-                            value: v.to_string(),
-                        })
-                        .collect::<Vec<_>>();
+            let name = series
+                .labels
+                .iter()
+                .find(|l| l.name == LABEL_NAME)
+                .map(|l| l.value.as_str())
+                .unwrap_or_default();
+            let mut seen = std::collections::HashSet::new();
+            for label in &series.labels {
+                if !is_valid_label_name(&label.name) {
+                    problems.push(format!(
+                        "series {index} ('{name}'): invalid label name '{}'",
+                        label.name
+                    ));
+                }
+                if !seen.insert(label.name.as_str()) {
+                    problems.push(format!(
+                        "series {index} ('{name}'): duplicate label '{}'",
+                        label.name
+                    ));
+                }
+            }
+            if series.samples.is_empty() {
+                problems.push(format!("series {index} ('{name}'): no samples"));
+            }
+        }
+        if let Err(err) = self.check_sorted() {
+            problems.push(err.to_string());
+        }
 
-                    TimeSeries {
-                        labels,
-                        samples: vec![],
-                    }
-                });
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join("\n").into())
+        }
+    }
 
-                let value = match sample.value {
-                    prometheus_parse::Value::Counter(v) => v,
-                    prometheus_parse::Value::Gauge(v) => v,
-                    prometheus_parse::Value::Histogram(_) => {
-                        Err("histogram not supported yet".to_string())?
-                    }
-                    prometheus_parse::Value::Summary(_) => {
-                        Err("summary not supported yet".to_string())?
-                    }
-                    prometheus_parse::Value::Untyped(v) => v,
-                };
+    /// Check that the request is already in the order required by the
+    /// specification, without modifying it.
+    ///
+    /// Returns an error describing the first offending series and field.
+    /// Useful in tests to catch producers that rely on [`Self::sort`].
+    pub fn check_sorted(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        for (index, series) in self.timeseries.iter().enumerate() {
+            let name = series
+                .labels
+                .iter()
+                .find(|l| l.name == LABEL_NAME)
+                .map(|l| l.value.as_str())
+                .unwrap_or_default();
 
-                series.samples.push(Sample {
-                    value,
-                    timestamp: sample.timestamp.timestamp_millis(),
-                });
+            if let Some(w) = series.labels.windows(2).find(|w| w[0].name > w[1].name) {
+                return Err(format!(
+                    "series {index} ('{name}'): label '{}' must be sorted before label '{}'",
+                    w[1].name, w[0].name
+                )
+                .into());
             }
+            if let Some(w) = series
+                .samples
+                .windows(2)
+                .find(|w| w[0].timestamp > w[1].timestamp)
+            {
+                return Err(format!(
+                    "series {index} ('{name}'): sample with timestamp {} must be sorted before timestamp {}",
+                    w[1].timestamp, w[0].timestamp
+                )
+                .into());
+            }
+            if let Some(w) = series
+                .exemplars
+                .windows(2)
+                .find(|w| w[0].timestamp > w[1].timestamp)
+            {
+                return Err(format!(
+                    "series {index} ('{name}'): exemplar with timestamp {} must be sorted before timestamp {}",
+                    w[1].timestamp, w[0].timestamp
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
 
-            Ok(all_series.into_values().collect())
+    /// Remove all time series and metadata, keeping the allocated capacity.
+    ///
+    /// Allows re-using a request in a loop without re-allocating the
+    /// series vector on every iteration.
+    pub fn clear(&mut self) {
+        self.timeseries.clear();
+        self.metadata.clear();
+    }
+
+    /// Remove all samples from every time series, keeping the series, their
+    /// labels and the allocated sample capacity.
+    ///
+    /// Useful when repeatedly pushing the same set of series:
+    ///
+    /// ```
+    /// # use prometheus_remote_write::{Label, Sample, TimeSeries, WriteRequest, LABEL_NAME};
+    /// let mut req = WriteRequest {
+    ///     timeseries: vec![TimeSeries {
+    ///         labels: vec![Label {
+    ///             name: LABEL_NAME.to_string(),
+    ///             value: "queue_size".to_string(),
+    ///         }],
+    ///         samples: Vec::with_capacity(1),
+    ///         exemplars: vec![],
+    ///     }],
+    ///     metadata: vec![],
+    /// };
+    ///
+    /// for i in 0..3 {
+    ///     req.clear_samples();
+    ///     req.timeseries[0].samples.push(Sample {
+    ///         value: i as f64,
+    ///         timestamp: i,
+    ///     });
+    ///     // Send a clone of the request ...
+    /// }
+    /// ```
+    pub fn clear_samples(&mut self) {
+        for series in &mut self.timeseries {
+            series.samples.clear();
         }
+    }
 
-        let iter = text.trim().lines().map(|x| Ok(x.to_string()));
-        let parsed = prometheus_parse::Scrape::parse(iter)
-            .map_err(|err| format!("could not parse input as Prometheus text format: {err}"))?;
+    /// Remove all samples with a timestamp before `timestamp_millis`.
+    ///
+    /// Series that end up without any samples are removed as well.
+    /// Useful to avoid out-of-order rejections when replaying old data to
+    /// receivers that only accept samples newer than their head.
+    pub fn drop_samples_before(&mut self, timestamp_millis: i64) {
+        for series in &mut self.timeseries {
+            series.samples.retain(|s| s.timestamp >= timestamp_millis);
+        }
+        self.timeseries.retain(|s| !s.samples.is_empty());
+    }
 
-        let mut series = samples_to_timeseries(parsed.samples)?;
-        series.sort_by(|a, b| {
-            let name_a = a.labels.iter().find(|x| x.name == LABEL_NAME).unwrap();
-            let name_b = b.labels.iter().find(|x| x.name == LABEL_NAME).unwrap();
-            name_a.value.cmp(&name_b.value)
-        });
+    /// Cap sample timestamps that lie after `now_millis` to `now_millis`.
+    ///
+    /// Receivers usually reject samples too far in the future. A series can
+    /// only have one sample per timestamp, so only the latest future sample of
+    /// each series is kept, and it replaces any sample at `now_millis`.
+    pub fn clamp_future_samples(&mut self, now_millis: i64) {
+        for series in &mut self.timeseries {
+            let Some(latest) = series
+                .samples
+                .iter()
+                .filter(|s| s.timestamp > now_millis)
+                .max_by_key(|s| s.timestamp)
+                .map(|s| s.value)
+            else {
+                continue;
+            };
+            series.samples.retain(|s| s.timestamp < now_millis);
+            series.samples.push(Sample {
+                value: latest,
+                timestamp: now_millis,
+            });
+        }
+    }
 
-        let s = Self { timeseries: series };
+    /// Downsample all series, see [`TimeSeries::downsample`].
+    pub fn downsample(&mut self, interval_millis: i64) {
+        for series in &mut self.timeseries {
+            series.downsample(interval_millis);
+        }
+    }
 
-        Ok(s.sorted())
+    /// Round all sample values to the given number of decimal places.
+    ///
+    /// NaN and infinite values are left untouched, as are values that are
+    /// too large to be rounded at the requested precision.
+    pub fn round_values(&mut self, decimals: u32) {
+        let factor = 10f64.powi(decimals.min(i32::MAX as u32) as i32);
+        for series in &mut self.timeseries {
+            for sample in &mut series.samples {
+                let scaled = sample.value * factor;
+                if scaled.is_finite() {
+                    sample.value = scaled.round() / factor;
+                }
+            }
+        }
     }
 
-    /// Build a fully prepared HTTP request that an be sent to a remote write endpoint.
-    #[cfg(feature = "http")]
-    pub fn build_http_request(
-        self,
-        endpoint: &url::Url,
-        user_agent: &str,
-    ) -> Result<http::Request<Vec<u8>>, Box<dyn std::error::Error + Send + Sync>> {
-        let req = http::Request::builder()
-            .method(http::Method::POST)
-            .uri(endpoint.as_str())
-            .header(http::header::CONTENT_TYPE, CONTENT_TYPE)
-            .header(HEADER_NAME_REMOTE_WRITE_VERSION, REMOTE_WRITE_VERSION_01)
-            .header(http::header::CONTENT_ENCODING, "snappy")
-            .header(http::header::USER_AGENT, user_agent)
-            .body(self.encode_compressed()?)?;
+    /// Return an error if the request contains more than `limit` time series.
+    ///
+    /// Protects receivers from requests with runaway label cardinality.
+    pub fn enforce_max_series(
+        &self,
+        limit: usize,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let count = self.timeseries.len();
+        if count > limit {
+            return Err(
+                format!("request contains {count} series, exceeding the limit of {limit}").into(),
+            );
+        }
+        Ok(())
+    }
 
-        Ok(req)
+    /// Return an error if any time series has more than `limit` labels,
+    /// including the metric name label.
+    pub fn enforce_max_labels_per_series(
+        &self,
+        limit: usize,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        for series in &self.timeseries {
+            let count = series.labels.len();
+            if count > limit {
+                let name = series
+                    .labels
+                    .iter()
+                    .find(|l| l.name == LABEL_NAME)
+                    .map(|l| l.value.as_str())
+                    .unwrap_or_default();
+                return Err(format!(
+                    "series '{name}' has {count} labels, exceeding the limit of {limit}"
+                )
+                .into());
+            }
+        }
+        Ok(())
     }
-}
 
-/// A time series.
-///
-/// .proto:
-/// ```protobuf
-/// message TimeSeries {
-///   repeated Label labels   = 1;
-///   repeated Sample samples = 2;
-/// }
-/// ```
-#[derive(prost::Message, Clone, PartialEq)]
-pub struct TimeSeries {
-    #[prost(message, repeated, tag = "1")]
-    pub labels: Vec<Label>,
-    #[prost(message, repeated, tag = "2")]
-    pub samples: Vec<Sample>,
-}
+    /// Return an error if any label value is longer than `max_len` bytes.
+    ///
+    /// See [`Self::truncate_label_values`] for a lenient alternative.
+    pub fn enforce_max_label_value_len(
+        &self,
+        max_len: usize,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        for series in &self.timeseries {
+            if let Some(label) = series.labels.iter().find(|l| l.value.len() > max_len) {
+                return Err(format!(
+                    "value of label '{}' is {} bytes long, exceeding the limit of {max_len}",
+                    label.name,
+                    label.value.len()
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
 
-impl TimeSeries {
-    /// Sort labels by name, and the samples by timestamp.
+    /// Truncate label values longer than `max_len` bytes.
     ///
-    /// Required by the specification.
-    pub fn sort_labels_and_samples(&mut self) {
-        self.labels.sort_by(|a, b| a.name.cmp(&b.name));
-        self.samples.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+    /// Values are cut at the last char boundary within the limit, so they
+    /// stay valid UTF-8. This includes the metric name label.
+    ///
+    /// Truncation can make distinct series identical; use
+    /// [`Self::normalize`] afterwards to merge them.
+    pub fn truncate_label_values(&mut self, max_len: usize) {
+        for series in &mut self.timeseries {
+            for label in &mut series.labels {
+                if label.value.len() > max_len {
+                    let mut end = max_len;
+                    while !label.value.is_char_boundary(end) {
+                        end -= 1;
+                    }
+                    label.value.truncate(end);
+                }
+            }
+        }
     }
-}
 
-/// A label.
-///
-/// .proto:
-/// ```protobuf
-/// message Label {
-///   string name  = 1;
-///   string value = 2;
-/// }
-/// ```
-#[derive(prost::Message, Clone, Hash, PartialEq, Eq)]
-pub struct Label {
-    #[prost(string, tag = "1")]
-    pub name: String,
-    #[prost(string, tag = "2")]
-    pub value: String,
-}
+    /// Only keep the series for which `predicate` returns `true`, and sort
+    /// the request.
+    ///
+    /// Metadata of a family is dropped when the filter removes all of the
+    /// family's series. Series belong to a family if they are named like it,
+    /// or like it with a suffix such as `_bucket` or `_total`. Metadata of
+    /// families without any series is kept.
+    ///
+    /// ```
+    /// use prometheus_remote_write::{WriteRequest, LABEL_NAME};
+    ///
+    /// let mut req = WriteRequest::from_samples(vec![
+    ///     ("http_requests_total".to_string(), vec![], 1.0, 1),
+    ///     ("process_cpu_seconds".to_string(), vec![], 2.0, 1),
+    /// ]);
+    /// req.retain_series(|series| {
+    ///     series
+    ///         .labels
+    ///         .iter()
+    ///         .any(|l| l.name == LABEL_NAME && l.value.starts_with("http_"))
+    /// });
+    /// assert_eq!(req.timeseries.len(), 1);
+    /// ```
+    pub fn retain_series(&mut self, predicate: impl FnMut(&TimeSeries) -> bool) {
+        let series_names = |timeseries: &[TimeSeries]| {
+            timeseries
+                .iter()
+                .filter_map(|series| series.labels.iter().find(|l| l.name == LABEL_NAME))
+                .map(|l| l.value.clone())
+                .collect::<std::collections::HashSet<_>>()
+        };
 
-/// A sample.
-///
-/// .proto:
-/// ```protobuf
-/// message Sample {
-///   double value    = 1;
-///   int64 timestamp = 2;
-/// }
-/// ```
-#[derive(prost::Message, Clone, PartialEq)]
-pub struct Sample {
-    #[prost(double, tag = "1")]
-    pub value: f64,
-    #[prost(int64, tag = "2")]
-    pub timestamp: i64,
-}
+        let names = series_names(&self.timeseries);
+        let had_series = self
+            .metadata
+            .iter()
+            .map(|m| family_has_series(&m.metric_family_name, &names))
+            .collect::<Vec<_>>();
 
-#[cfg(all(feature = "parse", feature = "compression"))]
-#[cfg(test)]
-mod tests {
-    use pretty_assertions::assert_eq;
+        self.timeseries.retain(predicate);
 
-    use super::*;
-    #[test]
-    fn test_name() {
-        let input = r#"
-# TYPE mycounter counter
-# TYPE mygauge gauge
+        let names = series_names(&self.timeseries);
+        let mut had_series = had_series.into_iter();
+        self.metadata.retain(|m| {
+            !had_series.next().unwrap_or_default()
+                || family_has_series(&m.metric_family_name, &names)
+        });
+        self.sort();
+    }
 
-mygauge 100 100
-http_requests_total{method="post",code="200"} 1027 1395066363000
-mycounter 100 100
-alpha 10 1000
-http_requests_total{method="post",code="200"} 50 1000
-    "#;
+    /// Split the request into one request per metric name.
+    ///
+    /// The returned map is keyed by the value of the [`LABEL_NAME`] label.
+    /// Series without a name label are collected under the empty string key.
+    pub fn split_by_metric(&self) -> std::collections::HashMap<String, WriteRequest> {
+        let mut groups = std::collections::HashMap::<String, WriteRequest>::new();
+        for series in &self.timeseries {
+            let name = series
+                .labels
+                .iter()
+                .find(|l| l.name == LABEL_NAME)
+                .map(|l| l.value.clone())
+                .unwrap_or_default();
+            groups
+                .entry(name.clone())
+                .or_insert_with(|| WriteRequest {
+                    timeseries: vec![],
+                    metadata: self
+                        .metadata
+                        .iter()
+                        .filter(|m| m.metric_family_name == name)
+                        .cloned()
+                        .collect(),
+                })
+                .timeseries
+                .push(series.clone());
+        }
+        groups
+    }
 
-        let req = WriteRequest::from_text_format(input.to_string()).unwrap();
+    /// The series of the request.
+    ///
+    /// Prefer this over accessing the `timeseries` field directly.
+    pub fn series(&self) -> &[TimeSeries] {
+        &self.timeseries
+    }
 
-        assert_eq!(
-            req,
-            WriteRequest {
-                timeseries: vec![
-                    TimeSeries {
-                        labels: vec![Label {
-                            name: LABEL_NAME.to_string(),
-                            value: "alpha".to_string()
-                        },],
-                        samples: vec![Sample {
-                            value: 10.0,
-                            timestamp: 1000,
-                        },]
-                    },
-                    TimeSeries {
-                        labels: vec![
-                            Label {
-                                name: LABEL_NAME.to_string(),
-                                value: "http_requests_total".to_string()
-                            },
-                            Label {
-                                name: "code".to_string(),
-                                value: "200".to_string()
-                            },
-                            Label {
-                                name: "method".to_string(),
+    /// Mutable access to the series of the request.
+    ///
+    /// Series can be modified in place, but not added or removed. Changing
+    /// labels may break the sort order, see [`Self::sort`].
+    pub fn series_mut(&mut self) -> &mut [TimeSeries] {
+        &mut self.timeseries
+    }
+
+    /// Iterate over all individual samples, together with the labels of
+    /// their series.
+    pub fn iter_samples(&self) -> impl Iterator<Item = (&[Label], &Sample)> + '_ {
+        self.timeseries.iter().flat_map(|series| {
+            series
+                .samples
+                .iter()
+                .map(move |sample| (series.labels.as_slice(), sample))
+        })
+    }
+
+    /// The minimum and maximum sample timestamp across all series, or `None`
+    /// if the request contains no samples.
+    ///
+    /// Useful to spot inputs with timestamps in the wrong unit, like seconds
+    /// instead of milliseconds.
+    pub fn timestamp_range(&self) -> Option<(i64, i64)> {
+        self.iter_samples().fold(None, |range, (_, sample)| {
+            let ts = sample.timestamp;
+            Some(match range {
+                Some((min, max)) => (ts.min(min), ts.max(max)),
+                None => (ts, ts),
+            })
+        })
+    }
+
+    /// Summarize the contents of the request.
+    ///
+    /// ```
+    /// # use prometheus_remote_write::{WriteRequest, WriteRequestStats};
+    /// let req = WriteRequest::from_samples(vec![
+    ///     ("up".to_string(), vec![("job".to_string(), "a".to_string())], 1.0, 1000),
+    ///     ("up".to_string(), vec![("job".to_string(), "b".to_string())], 1.0, 2000),
+    /// ]);
+    /// assert_eq!(req.stats().series, 2);
+    /// assert_eq!(req.stats().metrics, 1);
+    /// ```
+    pub fn stats(&self) -> WriteRequestStats {
+        let metrics = self
+            .timeseries
+            .iter()
+            .filter_map(|series| series.labels.iter().find(|l| l.name == LABEL_NAME))
+            .map(|l| l.value.as_str())
+            .collect::<std::collections::HashSet<_>>()
+            .len();
+        let range = self.timestamp_range();
+        WriteRequestStats {
+            series: self.timeseries.len(),
+            samples: self.timeseries.iter().map(|s| s.samples.len()).sum(),
+            metrics,
+            earliest: range.map(|(min, _)| min),
+            latest: range.map(|(_, max)| max),
+        }
+    }
+
+    /// Return the indices of series with implausible timestamps.
+    ///
+    /// A timestamp is implausible if it is off from `now_millis` by a factor
+    /// of 100 or more, which catches timestamps in seconds (landing in 1970)
+    /// as well as in micro- or nanoseconds. Returns nothing if `now_millis`
+    /// is not positive.
+    pub fn detect_suspicious_timestamps(&self, now_millis: i64) -> Vec<usize> {
+        if now_millis <= 0 {
+            return Vec::new();
+        }
+        let min = now_millis / 100;
+        let max = now_millis.saturating_mul(100);
+        self.timeseries
+            .iter()
+            .enumerate()
+            .filter(|(_, series)| {
+                series
+                    .samples
+                    .iter()
+                    .any(|s| s.timestamp < min || s.timestamp > max)
+            })
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Return the indices of counter series whose value decreases.
+    ///
+    /// A series is a counter if the metadata declares its metric family as
+    /// [`MetricMetadataType::Counter`]. Samples are compared in timestamp
+    /// order. A decrease means the counter was reset, for example by a
+    /// restart of the process, or that the metric is really a gauge.
+    pub fn check_counter_monotonicity(&self) -> Vec<usize> {
+        let counters = self
+            .metadata
+            .iter()
+            .filter(|m| m.r#type() == MetricMetadataType::Counter)
+            .map(|m| m.metric_family_name.as_str())
+            .collect::<std::collections::HashSet<_>>();
+        self.timeseries
+            .iter()
+            .enumerate()
+            .filter(|(_, series)| {
+                let is_counter = series
+                    .labels
+                    .iter()
+                    .any(|l| l.name == LABEL_NAME && counters.contains(l.value.as_str()));
+                if !is_counter {
+                    return false;
+                }
+                let mut samples = series.samples.iter().collect::<Vec<_>>();
+                samples.sort_by_key(|s| s.timestamp);
+                samples.windows(2).any(|w| w[1].value < w[0].value)
+            })
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Return the indices of series with values beyond 2^53.
+    ///
+    /// Above that, not every integer can be represented as an `f64`, so
+    /// large counters may have silently lost precision.
+    pub fn check_integer_precision(&self) -> Vec<usize> {
+        const MAX_SAFE_INTEGER: f64 = (1u64 << 53) as f64;
+        self.timeseries
+            .iter()
+            .enumerate()
+            .filter(|(_, series)| {
+                series
+                    .samples
+                    .iter()
+                    .any(|s| s.value.is_finite() && s.value.abs() > MAX_SAFE_INTEGER)
+            })
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Encode this write request as a protobuf message.
+    ///
+    /// NOTE: The API requires snappy compression, not a raw protobuf message.
+    pub fn encode_proto3(mut self) -> Vec<u8> {
+        // Fast path for requests with a single sample, as produced by the CLI
+        // for a manually specified metric: only the labels can be out of
+        // order, and they usually are not.
+        if let [series] = self.timeseries.as_mut_slice() {
+            if series.samples.len() <= 1 && series.exemplars.len() <= 1 {
+                if !series.labels.windows(2).all(|w| w[0].name <= w[1].name) {
+                    series.labels.sort_by(|a, b| a.name.cmp(&b.name));
+                }
+                return self.encode_proto3_unsorted();
+            }
+        }
+        self.sorted().encode_proto3_unsorted()
+    }
+
+    /// Encode this write request as a protobuf message without sorting it first.
+    ///
+    /// The caller is responsible for making sure labels and samples are
+    /// ordered as required by the specification, for example by calling
+    /// [`Self::sort`] beforehand.
+    pub fn encode_proto3_unsorted(self) -> Vec<u8> {
+        prost::Message::encode_to_vec(&self)
+    }
+
+    /// Encode this write request as a protobuf message into `buf`, like
+    /// [`Self::encode_proto3`].
+    ///
+    /// `buf` is cleared first, so it can be re-used across calls to avoid
+    /// allocations. Requests that are not sorted yet are cloned to sort them.
+    pub fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.clear();
+        if self.check_sorted().is_ok() {
+            buf.reserve(prost::Message::encoded_len(self));
+            // Encoding into a Vec can not run out of space.
+            prost::Message::encode(self, buf).expect("Vec<u8> grows as needed");
+        } else {
+            buf.extend_from_slice(&self.clone().encode_proto3());
+        }
+    }
+
+    /// Encode this write request as a snappy-compressed protobuf message into
+    /// `out`, like [`Self::encode_compressed`].
+    ///
+    /// The uncompressed message is written to `scratch`. Both buffers are
+    /// cleared first, so they can be re-used across calls.
+    #[cfg(feature = "compression")]
+    pub fn encode_compressed_into(
+        &self,
+        scratch: &mut Vec<u8>,
+        out: &mut Vec<u8>,
+    ) -> Result<(), snap::Error> {
+        self.encode_into(scratch);
+        out.clear();
+        out.resize(snap::raw::max_compress_len(scratch.len()), 0);
+        let len = snap::raw::Encoder::new().compress(scratch, out)?;
+        out.truncate(len);
+        Ok(())
+    }
+
+    /// Encode this write request as a snappy-compressed protobuf message.
+    #[cfg(feature = "compression")]
+    pub fn encode_compressed(self) -> Result<Vec<u8>, snap::Error> {
+        snap::raw::Encoder::new().compress_vec(&self.encode_proto3())
+    }
+
+    /// Encode this write request as a snappy-compressed protobuf message,
+    /// without sorting it first.
+    ///
+    /// See [`Self::encode_proto3_unsorted`].
+    #[cfg(feature = "compression")]
+    pub fn encode_compressed_unsorted(self) -> Result<Vec<u8>, snap::Error> {
+        snap::raw::Encoder::new().compress_vec(&self.encode_proto3_unsorted())
+    }
+
+    /// The size of the body produced by [`Self::encode_compressed`], without
+    /// keeping the compressed bytes.
+    ///
+    /// Compresses into a per-thread buffer that is re-used across calls, up
+    /// to a size of 1 MiB. Requests that are not sorted yet are cloned to
+    /// sort them, which needs as much memory again as the request itself;
+    /// call [`Self::sort`] first to avoid the copy.
+    #[cfg(feature = "compression")]
+    pub fn compressed_len(&self) -> Result<usize, snap::Error> {
+        thread_local! {
+            static BUFFER: std::cell::RefCell<Vec<u8>> = const { std::cell::RefCell::new(Vec::new()) };
+        }
+
+        let proto = if self.check_sorted().is_ok() {
+            prost::Message::encode_to_vec(self)
+        } else {
+            self.clone().encode_proto3()
+        };
+        BUFFER.with(|buffer| {
+            let mut buffer = buffer.borrow_mut();
+            let max_len = snap::raw::max_compress_len(proto.len());
+            if buffer.len() < max_len {
+                buffer.resize(max_len, 0);
+            }
+            let len = snap::raw::Encoder::new().compress(&proto, &mut buffer);
+            // Don't pin the memory of one large request for the life of the thread.
+            if buffer.len() > MAX_RETAINED_COMPRESS_BUFFER {
+                buffer.truncate(MAX_RETAINED_COMPRESS_BUFFER);
+                buffer.shrink_to_fit();
+            }
+            len
+        })
+    }
+
+    /// Encode this write request as a zstd-compressed protobuf message at
+    /// the given compression `level`.
+    ///
+    /// NOTE: The remote write specification requires snappy compression.
+    /// Prometheus rejects zstd bodies, only use this with receivers known to
+    /// accept them, like VictoriaMetrics. Send the body with
+    /// [`BodyEncoding::Zstd`].
+    #[cfg(feature = "zstd")]
+    pub fn encode_zstd(self, level: i32) -> std::io::Result<Vec<u8>> {
+        zstd::bulk::compress(&self.encode_proto3(), level)
+    }
+
+    /// Encode this write request, and return a reader producing the
+    /// protobuf message compressed with the snappy *framing* format.
+    ///
+    /// Allows streaming large bodies without buffering the compressed output.
+    ///
+    /// NOTE: The remote write specification requires the snappy *block*
+    /// format, as produced by [`Self::encode_compressed`]. Prometheus and most
+    /// other receivers reject framed bodies, so only use this with receivers
+    /// known to accept the framing format.
+    #[cfg(feature = "compression")]
+    pub fn encode_framed_reader(self) -> snap::read::FrameEncoder<std::io::Cursor<Vec<u8>>> {
+        snap::read::FrameEncoder::new(std::io::Cursor::new(self.encode_proto3()))
+    }
+
+    /// Split the request into batches whose body, as produced by
+    /// [`Self::encode_compressed`], is at most `max_compressed_bytes` long.
+    ///
+    /// The request is sorted, and series are packed greedily in order,
+    /// followed by the metadata. Returns an error if a single series or
+    /// metadata entry does not fit on its own.
+    ///
+    /// Only the tail of a batch is re-compressed when checking whether the
+    /// next series fits, so the cost stays linear in the size of the request.
+    #[cfg(feature = "compression")]
+    pub fn into_batches_by_compressed_size(
+        self,
+        max_compressed_bytes: usize,
+    ) -> Result<Vec<Self>, Box<dyn std::error::Error + Send + Sync>> {
+        enum Entry {
+            Series(TimeSeries),
+            Metadata(MetricMetadata),
+        }
+
+        let req = self.sorted();
+        let entries = req
+            .timeseries
+            .into_iter()
+            .map(Entry::Series)
+            .chain(req.metadata.into_iter().map(Entry::Metadata));
+
+        let mut batches = Vec::new();
+        let mut current = WriteRequest::default();
+        let mut tracker = CompressedSizeTracker::new();
+        let mut encoded = Vec::new();
+        for entry in entries {
+            encoded.clear();
+            match &entry {
+                Entry::Series(series) => {
+                    encoded.push(WRITE_REQUEST_TIMESERIES_KEY);
+                    prost::Message::encode_length_delimited(series, &mut encoded)?;
+                }
+                Entry::Metadata(metadata) => {
+                    encoded.push(WRITE_REQUEST_METADATA_KEY);
+                    prost::Message::encode_length_delimited(metadata, &mut encoded)?;
+                }
+            }
+
+            if !tracker.try_push(&encoded, max_compressed_bytes)? {
+                // Start a new batch, unless the entry did not fit on its own.
+                let fits = if current.timeseries.is_empty() && current.metadata.is_empty() {
+                    false
+                } else {
+                    batches.push(std::mem::take(&mut current));
+                    tracker.clear();
+                    tracker.try_push(&encoded, max_compressed_bytes)?
+                };
+                if !fits {
+                    let what = match &entry {
+                        Entry::Series(series) => format!(
+                            "series '{}'",
+                            series
+                                .labels
+                                .iter()
+                                .find(|l| l.name == LABEL_NAME)
+                                .map(|l| l.value.as_str())
+                                .unwrap_or_default()
+                        ),
+                        Entry::Metadata(metadata) => {
+                            format!("metadata for '{}'", metadata.metric_family_name)
+                        }
+                    };
+                    return Err(format!(
+                        "{what} does not fit into {max_compressed_bytes} compressed bytes"
+                    )
+                    .into());
+                }
+            }
+
+            match entry {
+                Entry::Series(series) => current.timeseries.push(series),
+                Entry::Metadata(metadata) => current.metadata.push(metadata),
+            }
+        }
+        if !current.timeseries.is_empty() || !current.metadata.is_empty() {
+            batches.push(current);
+        }
+        Ok(batches)
+    }
+
+    /// Encode many requests as snappy-compressed protobuf messages in parallel.
+    ///
+    /// Uses the global rayon thread pool. The results are in the same order
+    /// as the input.
+    #[cfg(feature = "rayon")]
+    pub fn encode_batches_parallel(batches: Vec<Self>) -> Vec<Result<Vec<u8>, snap::Error>> {
+        use rayon::prelude::*;
+
+        batches
+            .into_par_iter()
+            .map(Self::encode_compressed)
+            .collect()
+    }
+
+    /// Build a request from individual samples.
+    ///
+    /// Each item is a `(metric_name, labels, value, timestamp_millis)` tuple.
+    /// Samples with the same name and label set are grouped into a single
+    /// [`TimeSeries`]. The returned request is sorted.
+    pub fn from_samples(
+        samples: impl IntoIterator<Item = (String, Vec<(String, String)>, f64, i64)>,
+    ) -> Self {
+        let mut timeseries = group_samples(samples);
+        timeseries.sort_by(compare_series);
+        Self {
+            timeseries,
+            metadata: Vec::new(),
+        }
+        .sorted()
+    }
+
+    /// Build a request from typed samples.
+    ///
+    /// Like [`Self::from_samples`], but with a [`Metric`] instead of a plain
+    /// value. The type of every metric family is added to the metadata, so
+    /// it is available to receivers and to checks like
+    /// [`Self::check_counter_monotonicity`].
+    ///
+    /// Returns an error if a metric name is used with different types.
+    ///
+    /// ```
+    /// # use prometheus_remote_write::{Metric, WriteRequest};
+    /// let req = WriteRequest::from_metrics(vec![
+    ///     ("jobs_total".to_string(), vec![], Metric::Counter { value: 3.0 }, 1000),
+    ///     ("jobs_total".to_string(), vec![], Metric::Counter { value: 1.0 }, 2000),
+    /// ])
+    /// .unwrap();
+    /// assert_eq!(req.check_counter_monotonicity(), vec![0]);
+    /// ```
+    pub fn from_metrics(
+        metrics: impl IntoIterator<Item = (String, Vec<(String, String)>, Metric, i64)>,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let mut types = std::collections::BTreeMap::<String, MetricMetadataType>::new();
+        let mut samples = Vec::new();
+        for (name, labels, metric, timestamp) in metrics {
+            let kind = metric.metadata_type();
+            match types.get(&name) {
+                Some(existing) if *existing != kind => {
+                    return Err(format!(
+                        "metric '{name}' is used as both {} and {}",
+                        format!("{existing:?}").to_lowercase(),
+                        format!("{kind:?}").to_lowercase()
+                    )
+                    .into());
+                }
+                Some(_) => {}
+                None => {
+                    types.insert(name.clone(), kind);
+                }
+            }
+            samples.push((name, labels, metric.value(), timestamp));
+        }
+
+        let mut req = Self::from_samples(samples);
+        req.metadata = types
+            .into_iter()
+            .map(|(name, kind)| {
+                let mut metadata = MetricMetadata {
+                    metric_family_name: name,
+                    ..Default::default()
+                };
+                metadata.set_type(kind);
+                metadata
+            })
+            .collect();
+        Ok(req)
+    }
+
+    /// Parse metrics from the Prometheus text format, and convert them into a
+    /// [`WriteRequest`].
+    ///
+    /// Special float values are accepted in the spellings used by different
+    /// exporters, ignoring case: `NaN`, `+Inf`, `-Inf`, `Inf` and `Infinity`.
+    ///
+    /// Metrics of unsupported types (histograms and summaries) are errors,
+    /// which include the 1-based line of their first sample. Lines that can
+    /// not be parsed and samples with invalid values are skipped, and invalid
+    /// timestamps are replaced with the current time. Use
+    /// [`Self::from_text_format_verbose`] to report such input.
+    ///
+    /// Empty input, or input with only whitespace and comments, results in an
+    /// empty request.
+    #[cfg(feature = "parse")]
+    pub fn from_text_format(
+        text: String,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(Self::from_samples(strict_text_format_samples(&text)?))
+    }
+
+    /// Parse metrics from the Prometheus text format like
+    /// [`Self::from_text_format`], but keep the order of the input.
+    ///
+    /// Series appear in the order of their first sample, and samples in the
+    /// order of their lines. Such a request does not conform to the
+    /// specification; call [`Self::sorted`] before encoding it.
+    ///
+    /// ```
+    /// # use prometheus_remote_write::WriteRequest;
+    /// let text = "b 1 2000\nb 2 1000\na 3 1000\n".to_string();
+    /// let req = WriteRequest::from_text_format_preserve_order(text).unwrap();
+    /// assert_eq!(req.timeseries[0].samples[0].timestamp, 2000);
+    /// let req = req.sorted();
+    /// assert_eq!(req.timeseries[0].samples[0].timestamp, 1000);
+    /// ```
+    #[cfg(feature = "parse")]
+    pub fn from_text_format_preserve_order(
+        text: String,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(Self {
+            timeseries: group_samples(strict_text_format_samples(&text)?),
+            metadata: Vec::new(),
+        })
+    }
+
+    /// Parse metrics from the Prometheus text format like
+    /// [`Self::from_text_format`], but also report input that was dropped.
+    ///
+    /// Instead of failing, metrics of unsupported types (histograms and
+    /// summaries) are dropped with a warning. Lines that could not be parsed,
+    /// samples with invalid values, and invalid timestamps (which are
+    /// replaced by the current time) are reported as well.
+    #[cfg(feature = "parse")]
+    pub fn from_text_format_verbose(
+        text: String,
+    ) -> Result<(Self, Vec<ParseWarning>), Box<dyn std::error::Error + Send + Sync>> {
+        let mut warnings = text_line_problems(&text)
+            .map(|(line_number, line, problem)| ParseWarning {
+                line: Some(line_number),
+                message: match problem {
+                    TextLineProblem::Unparseable => {
+                        format!("could not parse line, skipped: '{}'", line.trim())
+                    }
+                    TextLineProblem::InvalidValue(_) => format!("{problem}, line skipped"),
+                    TextLineProblem::InvalidTimestamp(_) => {
+                        format!("{problem}, using the current time")
+                    }
+                },
+            })
+            .collect::<Vec<_>>();
+
+        let samples = text_format_samples(&text, |kind, metric| {
+            warnings.push(ParseWarning {
+                line: None,
+                message: format!("{kind} '{metric}' not supported yet, dropped"),
+            });
+            Ok(())
+        })?;
+
+        Ok((Self::from_samples(samples), warnings))
+    }
+
+    /// Parse metrics from the Prometheus text format, skipping what can not
+    /// be converted, and report what was skipped.
+    ///
+    /// Never fails on unsupported metric types (histograms and summaries),
+    /// malformed lines or invalid sample values. Each of them is reported as
+    /// a [`SkippedFamily`], in the order of the input lines. Invalid
+    /// timestamps are replaced by the current time and not reported; use
+    /// [`Self::from_text_format_verbose`] to see those too.
+    #[cfg(feature = "parse")]
+    pub fn from_text_format_partial(text: String) -> (Self, Vec<SkippedFamily>) {
+        let mut skipped = text_line_problems(&text)
+            .filter_map(|(line_number, line, problem)| {
+                let reason = match problem {
+                    TextLineProblem::Unparseable => SkipReason::MalformedLine,
+                    TextLineProblem::InvalidValue(value) => {
+                        SkipReason::InvalidValue(value.to_string())
+                    }
+                    TextLineProblem::InvalidTimestamp(_) => return None,
+                };
+                Some(SkippedFamily {
+                    name: leading_metric_name(line).to_string(),
+                    line: Some(line_number),
+                    reason,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut unsupported = Vec::new();
+        let samples = text_format_samples(&text, |kind, metric| {
+            let kind = match kind {
+                "histogram" => MetricMetadataType::Histogram,
+                "summary" => MetricMetadataType::Summary,
+                _ => MetricMetadataType::Unknown,
+            };
+            unsupported.push(SkippedFamily {
+                name: metric.to_string(),
+                line: metric_line_number(&text, metric),
+                reason: SkipReason::UnsupportedType(kind),
+            });
+            Ok(())
+        });
+        // Only the unsupported type callback can fail, and it never does.
+        let mut samples = samples.unwrap_or_default();
+        // The parser reports the `_sum` and `_count` series of histograms and
+        // summaries as separate metrics; skip them with their family.
+        samples.retain(|(name, ..)| {
+            !unsupported.iter().any(|family| {
+                name.strip_prefix(family.name.as_str())
+                    .is_some_and(|suffix| suffix == "_sum" || suffix == "_count")
+            })
+        });
+
+        skipped.extend(unsupported);
+        skipped.sort_by_key(|family| family.line);
+        (Self::from_samples(samples), skipped)
+    }
+
+    /// Parse metrics from the Prometheus text format, including `# TYPE`
+    /// and `# HELP` declarations as [`MetricMetadata`].
+    ///
+    /// Metadata is emitted for every declared metric family, including
+    /// families without samples. This allows metadata-only requests, for
+    /// example to register help text before any samples are written.
+    #[cfg(feature = "parse")]
+    pub fn from_text_format_with_metadata(
+        text: String,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let metadata = parse_text_metadata(&text);
+        let mut req = Self::from_text_format(text)?;
+        req.metadata = metadata;
+
+        Ok(req)
+    }
+
+    /// Convert a snapshot of the [`metrics_util`] debugging recorder into a
+    /// [`WriteRequest`].
+    ///
+    /// Counters and gauges produce a single sample. Histograms are expanded
+    /// into `_bucket` series (using [`DEFAULT_HISTOGRAM_BUCKETS`]), `_sum`
+    /// and `_count`. Metric names are sanitized like
+    /// [`Self::sanitize_names_legacy`], so the `.` commonly used with the
+    /// `metrics` crate is replaced by `_`.
+    /// All samples use `timestamp_millis`.
+    #[cfg(feature = "metrics")]
+    pub fn from_metrics_snapshot(
+        snapshot: metrics_util::debugging::Snapshot,
+        timestamp_millis: i64,
+    ) -> Self {
+        use metrics_util::debugging::DebugValue;
+
+        let mut samples = Vec::new();
+        for (key, _unit, _description, value) in snapshot.into_vec() {
+            let (_kind, key) = key.into_parts();
+            let mut name = key.name().to_string();
+            sanitize_legacy_name(&mut name, true);
+            let labels = key
+                .labels()
+                .map(|l| (l.key().to_string(), l.value().to_string()))
+                .collect::<Vec<_>>();
+
+            match value {
+                DebugValue::Counter(v) => {
+                    samples.push((name, labels, v as f64, timestamp_millis));
+                }
+                DebugValue::Gauge(v) => {
+                    samples.push((name, labels, v.into_inner(), timestamp_millis));
+                }
+                DebugValue::Histogram(values) => {
+                    let bounds = DEFAULT_HISTOGRAM_BUCKETS.iter().chain([&f64::INFINITY]);
+                    for bound in bounds {
+                        let count = values.iter().filter(|v| v.into_inner() <= *bound).count();
+                        let mut labels = labels.clone();
+                        labels.push(("le".to_string(), format_sample_value(*bound)));
+                        samples.push((
+                            format!("{name}_bucket"),
+                            labels,
+                            count as f64,
+                            timestamp_millis,
+                        ));
+                    }
+                    let sum = values.iter().map(|v| v.into_inner()).sum::<f64>();
+                    samples.push((format!("{name}_sum"), labels.clone(), sum, timestamp_millis));
+                    samples.push((
+                        format!("{name}_count"),
+                        labels,
+                        values.len() as f64,
+                        timestamp_millis,
+                    ));
+                }
+            }
+        }
+
+        Self::from_samples(samples)
+    }
+
+    /// Convert OpenTelemetry metrics (OTLP) into a [`WriteRequest`].
+    ///
+    /// Gauges, sums and explicit-bucket histograms are supported. Data point
+    /// attributes become labels, and resource attributes are added to every
+    /// series of the resource, unless a data point attribute has the same
+    /// name. Metric and label names are sanitized like
+    /// [`Self::sanitize_names_legacy`], and only string, boolean, integer and
+    /// float attribute values are used.
+    ///
+    /// Attributes whose names are equal after sanitizing (like `a.b` and
+    /// `a_b`) are merged into one label, joining their values with `;` in
+    /// the order of the original names. Attributes named like the labels set
+    /// by the conversion itself (`__name__`, and `le` for histograms) are
+    /// dropped.
+    ///
+    /// Monotonic sums become counters, other sums become gauges. Histograms
+    /// are expanded into cumulative `_bucket` series, `_sum` and `_count`.
+    /// Metric descriptions and units are kept as metadata. Data points
+    /// flagged as having no recorded value are skipped.
+    ///
+    /// Returns an error for exponential histograms, summaries, and sums or
+    /// histograms with delta temporality, which can not be represented.
+    ///
+    /// ```
+    /// # use prometheus_remote_write::{otlp::MetricsData, WriteRequest};
+    /// use prost::Message;
+    ///
+    /// # let body = MetricsData::default().encode_to_vec();
+    /// let data = MetricsData::decode(body.as_slice()).unwrap();
+    /// let req = WriteRequest::from_otlp(&data).unwrap();
+    /// ```
+    #[cfg(feature = "otlp")]
+    pub fn from_otlp(
+        data: &otlp::MetricsData,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        use otlp::{metric::Data, number_data_point, AggregationTemporality};
+
+        let delta = AggregationTemporality::Delta as i32;
+        let recorded = |flags: u32| flags & otlp::FLAG_NO_RECORDED_VALUE == 0;
+
+        let mut samples = Vec::new();
+        let mut metadata = std::collections::BTreeMap::<String, MetricMetadata>::new();
+        for resource_metrics in &data.resource_metrics {
+            let resource_labels = resource_metrics
+                .resource
+                .as_ref()
+                .map(|r| otlp_labels(&r.attributes))
+                .unwrap_or_default();
+            let labels_for = |attributes, reserved: &[&str]| {
+                let mut labels = resource_labels.clone();
+                labels.extend(otlp_labels(attributes));
+                labels.retain(|name, _| name != LABEL_NAME && !reserved.contains(&name.as_str()));
+                labels.into_iter().collect::<Vec<_>>()
+            };
+
+            for metric in resource_metrics
+                .scope_metrics
+                .iter()
+                .flat_map(|s| &s.metrics)
+            {
+                let mut name = metric.name.clone();
+                sanitize_legacy_name(&mut name, true);
+
+                let (kind, points) = match &metric.data {
+                    None => continue,
+                    Some(Data::Gauge(gauge)) => (MetricMetadataType::Gauge, &gauge.data_points),
+                    Some(Data::Sum(sum)) => {
+                        if sum.aggregation_temporality == delta {
+                            return Err(format!(
+                                "metric '{}': sums with delta temporality are not supported",
+                                metric.name
+                            )
+                            .into());
+                        }
+                        let kind = if sum.is_monotonic {
+                            MetricMetadataType::Counter
+                        } else {
+                            MetricMetadataType::Gauge
+                        };
+                        (kind, &sum.data_points)
+                    }
+                    Some(Data::Histogram(histogram)) => {
+                        if histogram.aggregation_temporality == delta {
+                            return Err(format!(
+                                "metric '{}': histograms with delta temporality are not supported",
+                                metric.name
+                            )
+                            .into());
+                        }
+                        for point in histogram.data_points.iter().filter(|p| recorded(p.flags)) {
+                            let labels = labels_for(&point.attributes, &["le"]);
+                            let timestamp = (point.time_unix_nano / 1_000_000) as i64;
+                            let bounds =
+                                point.explicit_bounds.iter().copied().chain([f64::INFINITY]);
+                            let mut cumulative = 0;
+                            for (bound, count) in bounds.zip(&point.bucket_counts) {
+                                cumulative += count;
+                                let mut labels = labels.clone();
+                                labels.push(("le".to_string(), format_sample_value(bound)));
+                                samples.push((
+                                    format!("{name}_bucket"),
+                                    labels,
+                                    cumulative as f64,
+                                    timestamp,
+                                ));
+                            }
+                            if let Some(sum) = point.sum {
+                                samples.push((
+                                    format!("{name}_sum"),
+                                    labels.clone(),
+                                    sum,
+                                    timestamp,
+                                ));
+                            }
+                            samples.push((
+                                format!("{name}_count"),
+                                labels,
+                                point.count as f64,
+                                timestamp,
+                            ));
+                        }
+                        metadata.insert(
+                            name.clone(),
+                            otlp_metadata(&name, MetricMetadataType::Histogram, metric),
+                        );
+                        continue;
+                    }
+                    Some(Data::ExponentialHistogram(_)) => {
+                        return Err(format!(
+                            "metric '{}': exponential histograms are not supported",
+                            metric.name
+                        )
+                        .into());
+                    }
+                    Some(Data::Summary(_)) => {
+                        return Err(format!(
+                            "metric '{}': summaries are not supported",
+                            metric.name
+                        )
+                        .into());
+                    }
+                };
+
+                for point in points.iter().filter(|p| recorded(p.flags)) {
+                    let value = match point.value {
+                        Some(number_data_point::Value::AsDouble(v)) => v,
+                        Some(number_data_point::Value::AsInt(v)) => v as f64,
+                        None => continue,
+                    };
+                    let timestamp = (point.time_unix_nano / 1_000_000) as i64;
+                    samples.push((
+                        name.clone(),
+                        labels_for(&point.attributes, &[]),
+                        value,
+                        timestamp,
+                    ));
+                }
+                metadata.insert(name.clone(), otlp_metadata(&name, kind, metric));
+            }
+        }
+
+        let mut req = Self::from_samples(samples);
+        req.metadata = metadata.into_values().collect();
+        Ok(req)
+    }
+
+    /// Render the request in the Prometheus text exposition format.
+    ///
+    /// This is the inverse of [`Self::from_text_format`]: every sample is
+    /// written as a `name{label="value",...} value timestamp` line.
+    /// Type and help metadata is not included.
+    pub fn to_text_format(&self) -> String {
+        self.to_string()
+    }
+
+    /// Render the request in the InfluxDB line protocol.
+    ///
+    /// The [`LABEL_NAME`] label is used as the measurement, all other labels
+    /// become tags, and the sample value is written as the `value` field.
+    /// Timestamps are converted to nanoseconds. Samples with NaN or infinite
+    /// values are skipped, since the line protocol can not represent them.
+    #[cfg(feature = "influx")]
+    pub fn to_influx_line_protocol(&self) -> String {
+        let mut out = String::new();
+        for series in &self.timeseries {
+            let mut line = String::new();
+            let name = series
+                .labels
+                .iter()
+                .find(|l| l.name == LABEL_NAME)
+                .map(|l| l.value.as_str())
+                .unwrap_or_default();
+            escape_influx(name, &[',', ' '], &mut line);
+
+            let mut tags = series
+                .labels
+                .iter()
+                .filter(|l| l.name != LABEL_NAME)
+                .collect::<Vec<_>>();
+            tags.sort_by(|a, b| a.name.cmp(&b.name));
+            for tag in tags {
+                line.push(',');
+                escape_influx(&tag.name, &[',', '=', ' '], &mut line);
+                line.push('=');
+                escape_influx(&tag.value, &[',', '=', ' '], &mut line);
+            }
+
+            for sample in &series.samples {
+                if !sample.value.is_finite() {
+                    continue;
+                }
+                out.push_str(&line);
+                out.push_str(&format!(
+                    " value={} {}\n",
+                    sample.value,
+                    i128::from(sample.timestamp) * 1_000_000
+                ));
+            }
+        }
+        out
+    }
+
+    /// Build a fully prepared HTTP request that an be sent to a remote write endpoint.
+    #[cfg(feature = "http")]
+    pub fn build_http_request(
+        self,
+        endpoint: &url::Url,
+        user_agent: &str,
+    ) -> Result<http::Request<Vec<u8>>, Box<dyn std::error::Error + Send + Sync>> {
+        self.build_http_request_with_compression(endpoint, user_agent, CompressionPolicy::Always)
+    }
+
+    /// Build a fully prepared HTTP request like [`Self::build_http_request`],
+    /// letting `customize` modify the request builder before the body is
+    /// attached.
+    ///
+    /// The builder passed to `customize` already has the method, URI and
+    /// User-Agent set, which may be changed. The content headers
+    /// (`Content-Type`, `Content-Encoding` and the remote write version) are
+    /// always set afterwards, replacing any values set by `customize`.
+    ///
+    /// ```
+    /// # use prometheus_remote_write::WriteRequest;
+    /// let endpoint = url::Url::parse("http://localhost:9090/api/v1/write").unwrap();
+    /// let req = WriteRequest::from_samples(vec![("up".to_string(), vec![], 1.0, 1)])
+    ///     .build_http_request_with(&endpoint, "my-agent", |builder| {
+    ///         builder.header("X-Scope-OrgID", "tenant-1")
+    ///     })
+    ///     .unwrap();
+    /// assert_eq!(req.headers()["x-scope-orgid"], "tenant-1");
+    /// ```
+    #[cfg(feature = "http")]
+    pub fn build_http_request_with(
+        self,
+        endpoint: &url::Url,
+        user_agent: &str,
+        customize: impl FnOnce(http::request::Builder) -> http::request::Builder,
+    ) -> Result<http::Request<Vec<u8>>, Box<dyn std::error::Error + Send + Sync>> {
+        let body = self.encode_compressed()?;
+        build_http_request_customized(body, endpoint, user_agent, BodyEncoding::Snappy, customize)
+    }
+
+    /// Build a fully prepared HTTP request, compressing the body according to
+    /// the given [`CompressionPolicy`].
+    #[cfg(feature = "http")]
+    pub fn build_http_request_with_compression(
+        self,
+        endpoint: &url::Url,
+        user_agent: &str,
+        policy: CompressionPolicy,
+    ) -> Result<http::Request<Vec<u8>>, Box<dyn std::error::Error + Send + Sync>> {
+        let proto = self.encode_proto3();
+        let (encoding, body) = match policy {
+            CompressionPolicy::Threshold(min_size) if proto.len() < min_size => {
+                (BodyEncoding::Identity, proto)
+            }
+            CompressionPolicy::Always | CompressionPolicy::Threshold(_) => (
+                BodyEncoding::Snappy,
+                snap::raw::Encoder::new().compress_vec(&proto)?,
+            ),
+        };
+
+        Self::build_http_request_from_bytes(body, endpoint, user_agent, encoding)
+    }
+
+    /// Build a fully prepared HTTP request from an already encoded body.
+    ///
+    /// Allows encoding a request once, and sending it to multiple endpoints.
+    /// `body` must be encoded as described by `encoding`, for example with
+    /// [`Self::encode_compressed`] for [`BodyEncoding::Snappy`].
+    #[cfg(feature = "http")]
+    pub fn build_http_request_from_bytes(
+        body: Vec<u8>,
+        endpoint: &url::Url,
+        user_agent: &str,
+        encoding: BodyEncoding,
+    ) -> Result<http::Request<Vec<u8>>, Box<dyn std::error::Error + Send + Sync>> {
+        build_http_request_customized(body, endpoint, user_agent, encoding, |builder| builder)
+    }
+
+    /// Encode the request and send it to `endpoint` with a prebuilt
+    /// [`ureq::Agent`].
+    ///
+    /// Reusing the agent across calls keeps connections alive between
+    /// requests. The request is sent once; retrying failed requests is left
+    /// to the caller, see [`SendError::is_retryable`].
+    ///
+    /// `ureq` only speaks HTTP/1.1. To send over HTTP/2, build the request
+    /// with [`Self::build_http_request`] and send it with an HTTP/2 capable
+    /// client instead.
+    #[cfg(feature = "ureq")]
+    pub fn send_ureq(
+        &self,
+        agent: &ureq::Agent,
+        endpoint: &url::Url,
+        user_agent: &str,
+    ) -> Result<SendStats, SendError> {
+        let start = std::time::Instant::now();
+        let stats = self.stats();
+        let req = self
+            .clone()
+            .build_http_request(endpoint, user_agent)
+            .map_err(|err| SendError::new("could not build HTTP request").with_source(err))?;
+        let (parts, body) = req.into_parts();
+
+        let status = send_ureq_request(build_ureq_request(agent, &parts, endpoint)?, &body)?;
+        Ok(SendStats {
+            series: stats.series,
+            samples: stats.samples,
+            compressed_bytes: body.len(),
+            status,
+            elapsed: start.elapsed(),
+        })
+    }
+
+    /// Send several requests to `endpoint` one after another, for example
+    /// the batches from [`Self::into_batches_by_compressed_size`].
+    ///
+    /// With [`SendPolicy::FailFast`], the first failed batch stops sending.
+    /// With [`SendPolicy::ContinueOnError`], all batches are attempted. In
+    /// both cases the error lists every failure, and the stats of the
+    /// batches that were sent.
+    ///
+    /// Each batch is sent once, see [`send_batches`] for retrying.
+    #[cfg(feature = "ureq")]
+    pub fn send_batches_ureq(
+        batches: &[WriteRequest],
+        agent: &ureq::Agent,
+        endpoint: &url::Url,
+        user_agent: &str,
+        policy: SendPolicy,
+    ) -> Result<Vec<SendStats>, BatchSendError> {
+        send_batches(batches, policy, |_index, batch| {
+            batch.send_ureq(agent, endpoint, user_agent)
+        })
+    }
+}
+
+/// Send several batches one after another with `send`, following `policy`.
+///
+/// `send` is called with the index and the batch, and can for example retry
+/// failed requests or send pre-encoded bodies. This is the loop behind
+/// [`WriteRequest::send_batches_ureq`].
+#[cfg(feature = "ureq")]
+pub fn send_batches<T>(
+    batches: &[T],
+    policy: SendPolicy,
+    mut send: impl FnMut(usize, &T) -> Result<SendStats, SendError>,
+) -> Result<Vec<SendStats>, BatchSendError> {
+    let mut sent = Vec::with_capacity(batches.len());
+    let mut failures = Vec::new();
+    for (index, batch) in batches.iter().enumerate() {
+        match send(index, batch) {
+            Ok(stats) => sent.push(stats),
+            Err(err) => {
+                failures.push((index, err));
+                if policy == SendPolicy::FailFast {
+                    break;
+                }
+            }
+        }
+    }
+
+    if failures.is_empty() {
+        Ok(sent)
+    } else {
+        let skipped = batches.len() - sent.len() - failures.len();
+        Err(BatchSendError {
+            total: batches.len(),
+            sent,
+            failures,
+            skipped,
+        })
+    }
+}
+
+/// How to proceed when one of several batches can not be sent.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SendPolicy {
+    /// Stop at the first batch that fails.
+    #[default]
+    FailFast,
+    /// Keep sending the remaining batches, and report all failures at the
+    /// end.
+    ContinueOnError,
+}
+
+/// Build an HTTP request for an encoded body, applying `customize` before
+/// setting the content headers.
+#[cfg(feature = "http")]
+fn build_http_request_customized(
+    body: Vec<u8>,
+    endpoint: &url::Url,
+    user_agent: &str,
+    encoding: BodyEncoding,
+    customize: impl FnOnce(http::request::Builder) -> http::request::Builder,
+) -> Result<http::Request<Vec<u8>>, Box<dyn std::error::Error + Send + Sync>> {
+    let builder = http::Request::builder()
+        .method(http::Method::POST)
+        .uri(endpoint.as_str())
+        .header(http::header::USER_AGENT, user_agent);
+    let mut builder = customize(builder);
+    if let Some(headers) = builder.headers_mut() {
+        headers.insert(
+            http::header::CONTENT_TYPE,
+            http::HeaderValue::from_static(CONTENT_TYPE),
+        );
+        headers.insert(
+            HEADER_NAME_REMOTE_WRITE_VERSION,
+            http::HeaderValue::from_static(REMOTE_WRITE_VERSION_01),
+        );
+        headers.insert(
+            http::header::CONTENT_ENCODING,
+            http::HeaderValue::from_static(encoding.as_str()),
+        );
+    }
+    let req = builder.body(body)?;
+
+    Ok(req)
+}
+
+/// Check whether `endpoint` looks like a remote write endpoint.
+///
+/// Returns a warning for the base URL of a server and for well known
+/// query, read and scrape paths like `/api/v1/query`, which are a common
+/// misconfiguration. Write paths differ between receivers (`/api/v1/write`,
+/// `/api/v1/push`, `/api/prom/push`, ...), so any other path is accepted.
+/// This is only a heuristic; [`WriteRequest::build_http_request`] does not
+/// check the endpoint.
+///
+/// ```
+/// # use prometheus_remote_write::endpoint_path_warning;
+/// let url = url::Url::parse("http://localhost:9090/api/v1/write").unwrap();
+/// assert_eq!(endpoint_path_warning(&url), None);
+/// let url = url::Url::parse("http://localhost:9090/api/v1/query").unwrap();
+/// assert!(endpoint_path_warning(&url).is_some());
+/// ```
+#[cfg(feature = "http")]
+pub fn endpoint_path_warning(endpoint: &url::Url) -> Option<String> {
+    const NON_WRITE_SEGMENTS: &[&str] = &[
+        "query",
+        "query_range",
+        "query_exemplars",
+        "read",
+        "series",
+        "labels",
+        "graph",
+        "metrics",
+        "federate",
+    ];
+
+    let path = endpoint.path().trim_end_matches('/');
+    if path.is_empty() {
+        return Some(format!(
+            "endpoint '{endpoint}' has no path, remote write endpoints usually \
+have a path like /api/v1/write"
+        ));
+    }
+    let last = path.rsplit('/').next().unwrap_or_default();
+    if NON_WRITE_SEGMENTS.contains(&last) {
+        return Some(format!(
+            "endpoint path '{path}' does not look like a remote write endpoint, \
+remote write endpoints usually have a path like /api/v1/write"
+        ));
+    }
+    None
+}
+
+/// Whether `code` is a successful (2xx) HTTP status code.
+#[cfg(feature = "http")]
+pub fn is_success_status(code: u16) -> bool {
+    (200..=299).contains(&code)
+}
+
+/// Classification of HTTP response status codes for remote write requests.
+#[cfg(feature = "http")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatusClass {
+    /// The request was accepted (2xx).
+    Success,
+    /// The request may succeed when retried (429 and 5xx).
+    Retryable,
+    /// The request will fail again when retried (all other codes).
+    Permanent,
+}
+
+#[cfg(feature = "http")]
+impl StatusClass {
+    /// Classify a status code.
+    ///
+    /// Follows the remote write specification: `429 Too Many Requests` and
+    /// server errors are retryable, other client errors are not.
+    pub fn of(code: u16) -> Self {
+        if is_success_status(code) {
+            StatusClass::Success
+        } else if code == 429 || (500..=599).contains(&code) {
+            StatusClass::Retryable
+        } else {
+            StatusClass::Permanent
+        }
+    }
+}
+
+/// Statistics about a successfully sent request.
+#[cfg(feature = "ureq")]
+#[derive(Clone, Debug, PartialEq)]
+pub struct SendStats {
+    pub series: usize,
+    pub samples: usize,
+    pub compressed_bytes: usize,
+    /// HTTP status code of the response.
+    pub status: u16,
+    /// Wall-clock time from the first attempt until the successful response.
+    ///
+    /// [`WriteRequest::send_ureq`] makes a single attempt, so this is the
+    /// duration of that request. Senders that retry should include failed
+    /// attempts and the delays between them.
+    pub elapsed: std::time::Duration,
+}
+
+#[cfg(feature = "ureq")]
+impl std::fmt::Display for SendStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let secs = self.elapsed.as_secs_f64();
+        write!(
+            f,
+            "{} series, {} samples, {} bytes in {:.3}s",
+            self.series, self.samples, self.compressed_bytes, secs
+        )?;
+        if secs > 0.0 {
+            write!(f, " ({:.0} bytes/s)", self.compressed_bytes as f64 / secs)?;
+        }
+        write!(f, ", status {}", self.status)
+    }
+}
+
+/// Error returned when sending a request with [`ureq`] fails.
+#[cfg(feature = "ureq")]
+#[derive(Debug)]
+pub struct SendError {
+    message: String,
+    status: Option<u16>,
+    retryable: bool,
+    retry_after: Option<std::time::Duration>,
+    source: Option<Box<dyn std::error::Error + Send + Sync>>,
+}
+
+#[cfg(feature = "ureq")]
+impl SendError {
+    fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            status: None,
+            retryable: false,
+            retry_after: None,
+            source: None,
+        }
+    }
+
+    fn with_source(mut self, source: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    /// Build the error for an error status response.
+    fn from_status(status: u16, res: ureq::Response) -> Self {
+        let retry_after = if status == 429 || status == 503 {
+            res.header("retry-after")
+                .and_then(|v| parse_retry_after(v, std::time::SystemTime::now()))
+        } else {
+            None
+        };
+        let body = response_body_excerpt(res);
+
+        Self {
+            message: format!("server returned error status code {status}: {body}"),
+            status: Some(status),
+            retryable: StatusClass::of(status) == StatusClass::Retryable,
+            retry_after,
+            source: None,
+        }
+    }
+
+    /// The HTTP status code, if the server responded with an error status.
+    pub fn status(&self) -> Option<u16> {
+        self.status
+    }
+
+    /// Whether the request may succeed when retried.
+    ///
+    /// True for transport errors, `429 Too Many Requests` and 5xx responses.
+    pub fn is_retryable(&self) -> bool {
+        self.retryable
+    }
+
+    /// Delay requested by the server with a `Retry-After` header.
+    ///
+    /// The delay is not capped, so a server can request arbitrarily long
+    /// waits. Clamp it to a maximum before sleeping.
+    pub fn retry_after(&self) -> Option<std::time::Duration> {
+        self.retry_after
+    }
+}
+
+#[cfg(feature = "ureq")]
+impl std::fmt::Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+#[cfg(feature = "ureq")]
+impl std::error::Error for SendError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source
+            .as_deref()
+            .map(|err| err as &(dyn std::error::Error + 'static))
+    }
+}
+
+/// Error returned by [`send_batches`] if any batch fails.
+#[cfg(feature = "ureq")]
+#[derive(Debug)]
+pub struct BatchSendError {
+    /// Total number of batches.
+    pub total: usize,
+    /// Stats of the batches that were sent successfully.
+    pub sent: Vec<SendStats>,
+    /// Index and error of every failed batch.
+    pub failures: Vec<(usize, SendError)>,
+    /// Number of batches that were not attempted with
+    /// [`SendPolicy::FailFast`].
+    pub skipped: usize,
+}
+
+#[cfg(feature = "ureq")]
+impl std::fmt::Display for BatchSendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} of {} batches failed",
+            self.failures.len(),
+            self.total
+        )?;
+        if self.skipped > 0 {
+            write!(f, ", {} not sent", self.skipped)?;
+        }
+        for (index, err) in &self.failures {
+            write!(f, "; batch {}: {err}", index + 1)?;
+            let mut source = std::error::Error::source(err);
+            while let Some(err) = source {
+                write!(f, ": {err}")?;
+                source = err.source();
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "ureq")]
+impl std::error::Error for BatchSendError {}
+
+/// Build a [`ureq::Request`] for `url` from the method and headers of
+/// prepared request parts.
+///
+/// The URI in `parts` is ignored in favour of `url`.
+#[cfg(feature = "ureq")]
+pub fn build_ureq_request(
+    agent: &ureq::Agent,
+    parts: &http::request::Parts,
+    url: &url::Url,
+) -> Result<ureq::Request, SendError> {
+    let mut req = agent.request(parts.method.as_str(), url.as_str());
+    for key in parts.headers.keys() {
+        for value in parts.headers.get_all(key) {
+            let value = value
+                .to_str()
+                .map_err(|err| SendError::new("non-utf8 http header value").with_source(err))?;
+            req = req.set(key.as_str(), value);
+        }
+    }
+    Ok(req)
+}
+
+/// Send an encoded body with a prepared [`ureq::Request`].
+///
+/// Returns the status code of a successful response. Error responses are
+/// turned into a [`SendError`] that includes an excerpt of the response body.
+#[cfg(feature = "ureq")]
+pub fn send_ureq_request(req: ureq::Request, body: &[u8]) -> Result<u16, SendError> {
+    let res = match req.send_bytes(body) {
+        Ok(res) => res,
+        Err(ureq::Error::Status(status, res)) => return Err(SendError::from_status(status, res)),
+        Err(ureq::Error::Transport(err)) => {
+            let mut err = SendError::new("could not send HTTP request").with_source(err);
+            err.retryable = true;
+            return Err(err);
+        }
+    };
+    let status = res.status();
+    if !is_success_status(status) {
+        return Err(SendError::from_status(status, res));
+    }
+
+    Ok(status)
+}
+
+/// Parse a Retry-After header value.
+///
+/// Supports both the delay-seconds and the HTTP-date form.
+/// Dates in the past result in a zero delay.
+#[cfg(feature = "ureq")]
+fn parse_retry_after(value: &str, now: std::time::SystemTime) -> Option<std::time::Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(std::time::Duration::from_secs(secs));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+/// Maximum number of characters of a response body to include in error messages.
+#[cfg(feature = "ureq")]
+const MAX_ERROR_BODY_CHARS: usize = 1024;
+
+/// Maximum number of response body bytes read for error messages.
+#[cfg(feature = "ureq")]
+const MAX_ERROR_BODY_BYTES: u64 = 1024 * 1024;
+
+/// Read the response body for inclusion in an error message.
+///
+/// Bodies with a gzip or deflate `Content-Encoding` are decoded first.
+/// The body is truncated to [`MAX_ERROR_BODY_CHARS`] characters.
+#[cfg(feature = "ureq")]
+fn response_body_excerpt(res: ureq::Response) -> String {
+    use std::io::Read;
+
+    let encoding = res
+        .header("content-encoding")
+        .map(|v| v.trim().to_ascii_lowercase());
+    let reader = res.into_reader().take(MAX_ERROR_BODY_BYTES);
+    let mut reader: Box<dyn Read> = match encoding.as_deref() {
+        Some("gzip" | "x-gzip") => Box::new(flate2::read::GzDecoder::new(reader)),
+        Some("deflate") => Box::new(flate2::read::ZlibDecoder::new(reader)),
+        _ => Box::new(reader),
+    };
+    let mut bytes = Vec::new();
+    if let Err(err) = reader
+        .by_ref()
+        .take(MAX_ERROR_BODY_BYTES)
+        .read_to_end(&mut bytes)
+    {
+        return format!("<could not read response body: {err}>");
+    }
+    let body = String::from_utf8_lossy(&bytes);
+    let body = body.trim();
+    if body.is_empty() {
+        return "<empty response body>".to_string();
+    }
+
+    match body.char_indices().nth(MAX_ERROR_BODY_CHARS) {
+        Some((index, _)) => format!("{}... (truncated)", &body[..index]),
+        None => body.to_string(),
+    }
+}
+
+/// Protobuf key of the `timeseries` field (1, length-delimited).
+#[cfg(feature = "compression")]
+const WRITE_REQUEST_TIMESERIES_KEY: u8 = (field_tags::WRITE_REQUEST_TIMESERIES as u8) << 3 | 2;
+/// Protobuf key of the `metadata` field (3, length-delimited).
+#[cfg(feature = "compression")]
+const WRITE_REQUEST_METADATA_KEY: u8 = (field_tags::WRITE_REQUEST_METADATA as u8) << 3 | 2;
+
+/// Block size of the snappy raw format.
+#[cfg(feature = "compression")]
+const SNAPPY_BLOCK_SIZE: usize = 1 << 16;
+
+/// Largest buffer kept per thread by [`WriteRequest::compressed_len`].
+#[cfg(feature = "compression")]
+const MAX_RETAINED_COMPRESS_BUFFER: usize = 1024 * 1024;
+
+/// Tracks the snappy-compressed size of a growing protobuf message.
+///
+/// Snappy compresses every 64 KiB block of the input independently, so the
+/// compressed size of complete blocks is computed once and cached. Only the
+/// last, incomplete block is compressed again after appending data.
+#[cfg(feature = "compression")]
+struct CompressedSizeTracker {
+    proto: Vec<u8>,
+    /// Compressed sizes of the complete blocks of `proto`.
+    block_sizes: Vec<usize>,
+    blocks_total: usize,
+    encoder: snap::raw::Encoder,
+    scratch: Vec<u8>,
+}
+
+#[cfg(feature = "compression")]
+impl CompressedSizeTracker {
+    fn new() -> Self {
+        Self {
+            proto: Vec::new(),
+            block_sizes: Vec::new(),
+            blocks_total: 0,
+            encoder: snap::raw::Encoder::new(),
+            scratch: Vec::new(),
+        }
+    }
+
+    /// Append `data` if the result compresses to at most `max_len` bytes.
+    ///
+    /// Returns whether the data was appended.
+    fn try_push(&mut self, data: &[u8], max_len: usize) -> Result<bool, snap::Error> {
+        let len = self.proto.len();
+        self.proto.extend_from_slice(data);
+
+        // Skip compressing if even incompressible data would fit.
+        let bound = snap::raw::max_compress_len(self.proto.len());
+        if bound != 0 && bound <= max_len {
+            return Ok(true);
+        }
+        if self.compressed_len()? <= max_len {
+            return Ok(true);
+        }
+
+        self.proto.truncate(len);
+        while self.block_sizes.len() * SNAPPY_BLOCK_SIZE > len {
+            self.blocks_total -= self.block_sizes.pop().unwrap_or_default();
+        }
+        Ok(false)
+    }
+
+    fn clear(&mut self) {
+        self.proto.clear();
+        self.block_sizes.clear();
+        self.blocks_total = 0;
+    }
+
+    /// The length of `snap::raw::Encoder::compress` output for `proto`.
+    fn compressed_len(&mut self) -> Result<usize, snap::Error> {
+        while (self.block_sizes.len() + 1) * SNAPPY_BLOCK_SIZE <= self.proto.len() {
+            let start = self.block_sizes.len() * SNAPPY_BLOCK_SIZE;
+            let size = self.block_len(start, start + SNAPPY_BLOCK_SIZE)?;
+            self.block_sizes.push(size);
+            self.blocks_total += size;
+        }
+
+        let start = self.block_sizes.len() * SNAPPY_BLOCK_SIZE;
+        let tail = if start < self.proto.len() {
+            self.block_len(start, self.proto.len())?
+        } else {
+            0
+        };
+        Ok(varint_len(self.proto.len()) + self.blocks_total + tail)
+    }
+
+    /// The compressed size of `proto[start..end]` as a single block, without
+    /// the length header.
+    fn block_len(&mut self, start: usize, end: usize) -> Result<usize, snap::Error> {
+        let block = &self.proto[start..end];
+        let max_len = snap::raw::max_compress_len(block.len());
+        if self.scratch.len() < max_len {
+            self.scratch.resize(max_len, 0);
+        }
+        let len = self.encoder.compress(block, &mut self.scratch)?;
+        Ok(len - varint_len(block.len()))
+    }
+}
+
+/// The number of bytes of `value` encoded as a varint.
+#[cfg(feature = "compression")]
+fn varint_len(mut value: usize) -> usize {
+    let mut len = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        len += 1;
+    }
+    len
+}
+
+/// Summary of the contents of a [`WriteRequest`], see [`WriteRequest::stats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WriteRequestStats {
+    /// Number of time series.
+    pub series: usize,
+    /// Number of samples across all series.
+    pub samples: usize,
+    /// Number of distinct metric names.
+    pub metrics: usize,
+    /// The earliest sample timestamp, in milliseconds.
+    pub earliest: Option<i64>,
+    /// The latest sample timestamp, in milliseconds.
+    pub latest: Option<i64>,
+}
+
+/// The encoding of an HTTP request body.
+///
+/// Variants depend on the enabled features (`Zstd` requires the `zstd`
+/// feature), so the enum is non-exhaustive.
+#[cfg(feature = "http")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BodyEncoding {
+    /// Snappy block compression, as required by the specification.
+    #[default]
+    Snappy,
+    /// Uncompressed protobuf.
+    Identity,
+    /// Zstd compression, see [`WriteRequest::encode_zstd`].
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+#[cfg(feature = "http")]
+impl BodyEncoding {
+    /// The value of the `Content-Encoding` header.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BodyEncoding::Snappy => "snappy",
+            BodyEncoding::Identity => "identity",
+            #[cfg(feature = "zstd")]
+            BodyEncoding::Zstd => "zstd",
+        }
+    }
+}
+
+/// Controls when HTTP request bodies are compressed.
+#[cfg(feature = "http")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CompressionPolicy {
+    /// Always snappy-compress the body.
+    ///
+    /// This is what the remote write specification requires.
+    #[default]
+    Always,
+    /// Only compress bodies with an uncompressed size of at least the given
+    /// number of bytes.
+    ///
+    /// Smaller bodies are sent uncompressed with `Content-Encoding: identity`.
+    /// Only use this with receivers known to accept uncompressed requests.
+    Threshold(usize),
+}
+
+/// Group samples into time series by metric name and label set.
+///
+/// Works in two passes: the first assigns every sample to its series and
+/// counts the samples per series, the second moves the samples into
+/// exactly sized vectors. This avoids repeated reallocations for series
+/// with many samples. Series are identified by their [`LabelSet`].
+///
+/// Series are returned in the order of their first sample, and samples in
+/// input order.
+fn group_samples(
+    samples: impl IntoIterator<Item = (String, Vec<(String, String)>, f64, i64)>,
+) -> Vec<TimeSeries> {
+    let samples = samples.into_iter();
+    let mut index = std::collections::HashMap::<LabelSet, usize>::new();
+    let mut series = Vec::<(Vec<Label>, usize)>::new();
+    let mut assigned = Vec::<(usize, Sample)>::with_capacity(samples.size_hint().0);
+
+    for (name, labels, value, timestamp) in samples {
+        let mut set_labels = Vec::with_capacity(labels.len() + 1);
+        set_labels.extend(
+            labels
+                .into_iter()
+                .map(|(name, value)| Label { name, value }),
+        );
+        set_labels.push(Label {
+            name: LABEL_NAME.to_string(),
+            value: name,
+        });
+        let set = LabelSet::new(set_labels);
+
+        let series_index = match index.get(&set) {
+            Some(index) => *index,
+            None => {
+                series.push((set.labels().to_vec(), 0));
+                index.insert(set, series.len() - 1);
+                series.len() - 1
+            }
+        };
+        series[series_index].1 += 1;
+        assigned.push((series_index, Sample { value, timestamp }));
+    }
+
+    let mut series = series
+        .into_iter()
+        .map(|(labels, count)| TimeSeries {
+            labels,
+            samples: Vec::with_capacity(count),
+            exemplars: vec![],
+        })
+        .collect::<Vec<_>>();
+    for (series_index, sample) in assigned {
+        series[series_index].samples.push(sample);
+    }
+
+    series
+}
+
+/// Collect the samples of a text format document, failing on the first
+/// metric of an unsupported type.
+///
+/// See [`WriteRequest::from_text_format`].
+#[cfg(feature = "parse")]
+fn strict_text_format_samples(
+    text: &str,
+) -> Result<Vec<TextSample>, Box<dyn std::error::Error + Send + Sync>> {
+    text_format_samples(text, |kind, metric| {
+        Err(match metric_line_number(text, metric) {
+            Some(line_number) => {
+                format!("line {line_number}: {kind} '{metric}' not supported yet")
+            }
+            None => format!("{kind} '{metric}' not supported yet"),
+        }
+        .into())
+    })
+}
+
+/// Suffixes of series names that belong to the metric family without the
+/// suffix, like the `_bucket` series of a histogram.
+const FAMILY_SUFFIXES: &[&str] = &["_bucket", "_sum", "_count", "_total", "_created", "_info"];
+
+/// Whether `names` contains a series name of the metric family `family`.
+fn family_has_series(family: &str, names: &std::collections::HashSet<String>) -> bool {
+    names.contains(family)
+        || FAMILY_SUFFIXES
+            .iter()
+            .any(|suffix| names.contains(&format!("{family}{suffix}")))
+}
+
+/// Total order over time series.
+///
+/// Orders by metric name first, then by the full label set.
+/// Labels must already be sorted by name.
+///
+/// Values of the histogram `le` and summary `quantile` labels are compared
+/// numerically, so buckets stay in their natural order (with `+Inf` last)
+/// when rendered.
+fn compare_series(a: &TimeSeries, b: &TimeSeries) -> std::cmp::Ordering {
+    fn compare_label(a: &Label, b: &Label) -> std::cmp::Ordering {
+        a.name.cmp(&b.name).then_with(|| {
+            if a.name == "le" || a.name == "quantile" {
+                if let (Ok(x), Ok(y)) = (a.value.parse::<f64>(), b.value.parse::<f64>()) {
+                    if let Some(ord) = x.partial_cmp(&y) {
+                        return ord;
+                    }
+                }
+            }
+            a.value.cmp(&b.value)
+        })
+    }
+
+    fn metric_name(series: &TimeSeries) -> &str {
+        series
+            .labels
+            .iter()
+            .find(|x| x.name == LABEL_NAME)
+            .map(|x| x.value.as_str())
+            .unwrap_or_default()
+    }
+
+    metric_name(a).cmp(metric_name(b)).then_with(|| {
+        let mut labels_b = b.labels.iter();
+        for label_a in &a.labels {
+            let Some(label_b) = labels_b.next() else {
+                return std::cmp::Ordering::Greater;
+            };
+            let ord = compare_label(label_a, label_b);
+            if ord.is_ne() {
+                return ord;
+            }
+        }
+        if labels_b.next().is_some() {
+            std::cmp::Ordering::Less
+        } else {
+            std::cmp::Ordering::Equal
+        }
+    })
+}
+
+/// Decode an uncompressed protobuf message.
+impl TryFrom<&[u8]> for WriteRequest {
+    type Error = prost::DecodeError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        prost::Message::decode(value)
+    }
+}
+
+/// Iterate over the time series of the request.
+///
+/// Metadata is discarded.
+impl IntoIterator for WriteRequest {
+    type Item = TimeSeries;
+    type IntoIter = std::vec::IntoIter<TimeSeries>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.timeseries.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a WriteRequest {
+    type Item = &'a TimeSeries;
+    type IntoIter = std::slice::Iter<'a, TimeSeries>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.timeseries.iter()
+    }
+}
+
+/// Build a request from time series, without metadata.
+///
+/// The series are taken as they are; they are sorted when the request is
+/// encoded.
+///
+/// ```
+/// use prometheus_remote_write::{Label, Sample, TimeSeries, WriteRequest, LABEL_NAME};
+///
+/// let series = |name: &str| TimeSeries {
+///     labels: vec![Label {
+///         name: LABEL_NAME.to_string(),
+///         value: name.to_string(),
+///     }],
+///     samples: vec![Sample {
+///         value: 1.0,
+///         timestamp: 1000,
+///     }],
+///     exemplars: vec![],
+/// };
+///
+/// let req = WriteRequest::from(vec![series("a"), series("b")]);
+/// assert_eq!(req.timeseries.len(), 2);
+///
+/// let collected: WriteRequest = ["a", "b"].into_iter().map(series).collect();
+/// assert_eq!(collected, req);
+/// ```
+impl From<Vec<TimeSeries>> for WriteRequest {
+    fn from(timeseries: Vec<TimeSeries>) -> Self {
+        Self {
+            timeseries,
+            metadata: Vec::new(),
+        }
+    }
+}
+
+/// Collect time series into a request without metadata.
+impl FromIterator<TimeSeries> for WriteRequest {
+    fn from_iter<I: IntoIterator<Item = TimeSeries>>(iter: I) -> Self {
+        Self::from(iter.into_iter().collect::<Vec<_>>())
+    }
+}
+
+/// Append time series to the request as they are.
+///
+/// Series are not merged with existing series that have the same labels,
+/// and nothing is sorted. Call [`WriteRequest::normalize`] afterwards if
+/// series may collide, or [`WriteRequest::sort`] otherwise.
+impl Extend<TimeSeries> for WriteRequest {
+    fn extend<I: IntoIterator<Item = TimeSeries>>(&mut self, iter: I) {
+        self.timeseries.extend(iter);
+    }
+}
+
+impl AsRef<[TimeSeries]> for WriteRequest {
+    fn as_ref(&self) -> &[TimeSeries] {
+        self.series()
+    }
+}
+
+/// Encode as an uncompressed protobuf message.
+///
+/// Equivalent to [`WriteRequest::encode_proto3`].
+impl From<WriteRequest> for Vec<u8> {
+    fn from(value: WriteRequest) -> Self {
+        value.encode_proto3()
+    }
+}
+
+impl std::fmt::Display for WriteRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for series in &self.timeseries {
+            let name = series
+                .labels
+                .iter()
+                .find(|l| l.name == LABEL_NAME)
+                .map(|l| l.value.as_str())
+                .unwrap_or_default();
+
+            let mut labels = String::new();
+            for label in series.labels.iter().filter(|l| l.name != LABEL_NAME) {
+                if !labels.is_empty() {
+                    labels.push(',');
+                }
+                labels.push_str(&label.name);
+                labels.push_str("=\"");
+                escape_label_value(&label.value, &mut labels);
+                labels.push('"');
+            }
+
+            for sample in &series.samples {
+                f.write_str(name)?;
+                if !labels.is_empty() {
+                    write!(f, "{{{labels}}}")?;
+                }
+                writeln!(
+                    f,
+                    " {} {}",
+                    format_sample_value(sample.value),
+                    sample.timestamp
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Escape a label value for the text exposition format.
+///
+/// Backslashes, double quotes and line feeds are escaped as `\\`, `\"` and
+/// `\n`.
+fn escape_label_value(value: &str, out: &mut String) {
+    for c in value.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+}
+
+/// Reverse [`escape_label_value`].
+///
+/// Unknown escape sequences are kept verbatim.
+#[cfg(feature = "parse")]
+fn unescape_label_value(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => out.push('\\'),
+            Some('"') => out.push('"'),
+            Some('n') => out.push('\n'),
+            Some(other) => {
+                out.push('\\');
+                out.push(other);
+            }
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+/// A `(metric_name, labels, value, timestamp_millis)` tuple.
+#[cfg(feature = "parse")]
+type TextSample = (String, Vec<(String, String)>, f64, i64);
+
+/// Parse text format input into `(metric_name, labels, value, timestamp)`
+/// tuples, as accepted by [`WriteRequest::from_samples`].
+///
+/// `unsupported` is called with the type and name of each metric with an
+/// unsupported type. The metric is dropped unless an error is returned.
+#[cfg(feature = "parse")]
+fn text_format_samples(
+    text: &str,
+    mut unsupported: impl FnMut(&str, &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>>,
+) -> Result<Vec<TextSample>, Box<dyn std::error::Error + Send + Sync>> {
+    let iter = text.trim().lines().map(|x| Ok(x.to_string()));
+    let parsed = prometheus_parse::Scrape::parse(iter)
+        .map_err(|err| format!("could not parse input as Prometheus text format: {err}"))?;
+
+    let mut samples = Vec::with_capacity(parsed.samples.len());
+    for sample in parsed.samples {
+        let value = match sample.value {
+            prometheus_parse::Value::Counter(v) => v,
+            prometheus_parse::Value::Gauge(v) => v,
+            prometheus_parse::Value::Histogram(_) => {
+                unsupported("histogram", &sample.metric)?;
+                continue;
+            }
+            prometheus_parse::Value::Summary(_) => {
+                unsupported("summary", &sample.metric)?;
+                continue;
+            }
+            prometheus_parse::Value::Untyped(v) => v,
+        };
+        let labels = sample
+            .labels
+            .iter()
+            .map(|(k, v)| (k.clone(), unescape_label_value(v)))
+            .collect::<Vec<_>>();
+
+        samples.push((
+            sample.metric,
+            labels,
+            value,
+            sample.timestamp.timestamp_millis(),
+        ));
+    }
+    Ok(samples)
+}
+
+/// A problem with a single line of text format input, which the parser
+/// would silently skip or paper over.
+#[cfg(feature = "parse")]
+enum TextLineProblem<'a> {
+    /// Not a comment, and not a valid sample.
+    Unparseable,
+    InvalidValue(&'a str),
+    InvalidTimestamp(&'a str),
+}
+
+#[cfg(feature = "parse")]
+impl std::fmt::Display for TextLineProblem<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TextLineProblem::Unparseable => write!(f, "could not parse line"),
+            TextLineProblem::InvalidValue(value) => write!(f, "invalid sample value '{value}'"),
+            TextLineProblem::InvalidTimestamp(ts) => write!(f, "invalid timestamp '{ts}'"),
+        }
+    }
+}
+
+/// Find problematic lines in text format input.
+///
+/// Yields the 1-based line number, the line, and the problem.
+#[cfg(feature = "parse")]
+fn text_line_problems(text: &str) -> impl Iterator<Item = (usize, &str, TextLineProblem<'_>)> {
+    text.lines().enumerate().filter_map(|(index, line)| {
+        let problem = match prometheus_parse::LineInfo::parse(line) {
+            prometheus_parse::LineInfo::Ignored if !line.trim().starts_with('#') => {
+                TextLineProblem::Unparseable
+            }
+            prometheus_parse::LineInfo::Sample {
+                value, timestamp, ..
+            } => {
+                if value.to_lowercase().parse::<f64>().is_err() {
+                    TextLineProblem::InvalidValue(value)
+                } else if let Some(ts) = timestamp.filter(|ts| ts.parse::<i64>().is_err()) {
+                    TextLineProblem::InvalidTimestamp(ts)
+                } else {
+                    return None;
+                }
+            }
+            _ => return None,
+        };
+        Some((index + 1, line, problem))
+    })
+}
+
+/// The 1-based number of the first sample line of the metric family
+/// `metric`, including histogram and summary series with a suffix.
+#[cfg(feature = "parse")]
+fn metric_line_number(text: &str, metric: &str) -> Option<usize> {
+    text.lines()
+        .position(|line| {
+            matches!(
+                prometheus_parse::LineInfo::parse(line),
+                prometheus_parse::LineInfo::Sample { metric_name, .. }
+                    if metric_name == metric
+                        || metric_name
+                            .strip_prefix(metric)
+                            .is_some_and(|suffix| suffix.starts_with('_'))
+            )
+        })
+        .map(|index| index + 1)
+}
+
+/// A problem encountered while parsing input, that did not prevent parsing
+/// the rest of the input.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseWarning {
+    /// The 1-based line number the warning refers to, if any.
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl std::fmt::Display for ParseWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {line}: {}", self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+/// A metric family, or a line of it, that was skipped while parsing, see
+/// [`WriteRequest::from_text_format_partial`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SkippedFamily {
+    /// Name of the metric family. Empty if a malformed line does not start
+    /// with a metric name.
+    pub name: String,
+    /// The 1-based number of the (first) skipped line, if known.
+    pub line: Option<usize>,
+    pub reason: SkipReason,
+}
+
+impl std::fmt::Display for SkippedFamily {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {line}: ")?;
+        }
+        write!(f, "'{}': {}", self.name, self.reason)
+    }
+}
+
+/// Why a [`SkippedFamily`] was skipped.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Metrics of this type can not be converted yet.
+    UnsupportedType(MetricMetadataType),
+    /// The line is neither a comment nor a valid sample.
+    MalformedLine,
+    /// The sample value is not a number.
+    InvalidValue(String),
+}
+
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SkipReason::UnsupportedType(kind) => {
+                write!(
+                    f,
+                    "{} not supported yet",
+                    format!("{kind:?}").to_lowercase()
+                )
+            }
+            SkipReason::MalformedLine => write!(f, "could not parse line"),
+            SkipReason::InvalidValue(value) => write!(f, "invalid sample value '{value}'"),
+        }
+    }
+}
+
+/// The metric name at the start of a text format line, or an empty string.
+#[cfg(feature = "parse")]
+fn leading_metric_name(line: &str) -> &str {
+    let line = line.trim_start();
+    let end = line
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == ':'))
+        .unwrap_or(line.len());
+    &line[..end]
+}
+
+/// Collect the `# TYPE` and `# HELP` declarations of text format input,
+/// ordered by metric family name.
+#[cfg(feature = "parse")]
+fn parse_text_metadata(text: &str) -> Vec<MetricMetadata> {
+    let mut families = std::collections::BTreeMap::<String, MetricMetadata>::new();
+    for line in text.lines() {
+        let Some(rest) = line.trim().strip_prefix('#') else {
+            continue;
+        };
+        let mut parts = rest.trim_start().splitn(3, char::is_whitespace);
+        let (Some(keyword), Some(name)) = (parts.next(), parts.next()) else {
+            continue;
+        };
+        let arg = parts.next().unwrap_or_default().trim();
+        let entry = || MetricMetadata {
+            metric_family_name: name.to_string(),
+            ..Default::default()
+        };
+        match keyword {
+            "TYPE" => {
+                let kind = match arg {
+                    "counter" => MetricMetadataType::Counter,
+                    "gauge" => MetricMetadataType::Gauge,
+                    "histogram" => MetricMetadataType::Histogram,
+                    "summary" => MetricMetadataType::Summary,
+                    _ => MetricMetadataType::Unknown,
+                };
+                families
+                    .entry(name.to_string())
+                    .or_insert_with(entry)
+                    .set_type(kind);
+            }
+            "HELP" => {
+                families.entry(name.to_string()).or_insert_with(entry).help = unescape_help(arg);
+            }
+            _ => {}
+        }
+    }
+    families.into_values().collect()
+}
+
+/// Unescape `\\` and `\n` in `# HELP` text.
+#[cfg(feature = "parse")]
+fn unescape_help(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+/// Sanitize a metric name (with `allow_colon`) or label name in place, see
+/// [`WriteRequest::sanitize_names_legacy`].
+fn sanitize_legacy_name(name: &mut String, allow_colon: bool) {
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || (allow_colon && c == ':');
+    if name.chars().all(valid) && !name.starts_with(|c: char| c.is_ascii_digit()) {
+        return;
+    }
+
+    let mut sanitized = String::with_capacity(name.len() + 1);
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        sanitized.push('_');
+    }
+    sanitized.extend(name.chars().map(|c| if valid(c) { c } else { '_' }));
+    *name = sanitized;
+}
+
+/// Convert OTLP attributes into labels with sanitized names, see
+/// [`WriteRequest::from_otlp`].
+///
+/// Values of attributes with the same sanitized name are joined with `;`,
+/// ordered by the original attribute name.
+#[cfg(feature = "otlp")]
+fn otlp_labels(attributes: &[otlp::KeyValue]) -> std::collections::BTreeMap<String, String> {
+    use otlp::any_value::Value;
+
+    let mut values = attributes
+        .iter()
+        .filter_map(|attr| {
+            let value = match attr.value.as_ref()?.value.as_ref()? {
+                Value::StringValue(v) => v.clone(),
+                Value::BoolValue(v) => v.to_string(),
+                Value::IntValue(v) => v.to_string(),
+                Value::DoubleValue(v) => format_sample_value(*v),
+            };
+            Some((attr.key.as_str(), value))
+        })
+        .collect::<Vec<_>>();
+    values.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut labels = std::collections::BTreeMap::<String, String>::new();
+    for (key, value) in values {
+        let mut name = key.to_string();
+        sanitize_legacy_name(&mut name, false);
+        labels
+            .entry(name)
+            .and_modify(|existing| {
+                existing.push(';');
+                existing.push_str(&value);
+            })
+            .or_insert(value);
+    }
+    labels
+}
+
+#[cfg(feature = "otlp")]
+fn otlp_metadata(name: &str, kind: MetricMetadataType, metric: &otlp::Metric) -> MetricMetadata {
+    let mut metadata = MetricMetadata {
+        metric_family_name: name.to_string(),
+        help: metric.description.clone(),
+        unit: metric.unit.clone(),
+        ..Default::default()
+    };
+    metadata.set_type(kind);
+    metadata
+}
+
+/// Escape a measurement name, tag key or tag value for the Influx line
+/// protocol, by prefixing the given characters and backslashes with a
+/// backslash.
+#[cfg(feature = "influx")]
+fn escape_influx(value: &str, special: &[char], out: &mut String) {
+    for c in value.chars() {
+        if c == '\\' || special.contains(&c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+}
+
+/// Format a sample value for the text exposition format.
+///
+/// Uses the shortest representation that parses back to the same value.
+/// Very large and very small magnitudes use scientific notation, which
+/// avoids hundreds of digits for values like `1e300` or subnormals.
+fn format_sample_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else if value != 0.0 && !(1e-4..1e21).contains(&value.abs()) {
+        format!("{value:e}")
+    } else {
+        value.to_string()
+    }
+}
+
+/// A time series.
+///
+/// .proto:
+/// ```protobuf
+/// message TimeSeries {
+///   repeated Label labels       = 1;
+///   repeated Sample samples     = 2;
+///   repeated Exemplar exemplars = 3;
+/// }
+/// ```
+#[derive(prost::Message, Clone, PartialEq)]
+pub struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    pub labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    pub samples: Vec<Sample>,
+    #[prost(message, repeated, tag = "3")]
+    pub exemplars: Vec<Exemplar>,
+}
+
+impl TimeSeries {
+    /// Create a new time series.
+    ///
+    /// The `__name__` label is set from the validated `name`, and labels and
+    /// samples are sorted as required by the specification.
+    pub fn new(name: MetricName, labels: Vec<Label>, samples: Vec<Sample>) -> Self {
+        let mut labels = labels;
+        labels.retain(|label| label.name != LABEL_NAME);
+        labels.push(Label {
+            name: LABEL_NAME.to_string(),
+            value: name.0,
+        });
+        let mut series = Self {
+            labels,
+            samples,
+            exemplars: Vec::new(),
+        };
+        series.sort_labels_and_samples();
+        series
+    }
+
+    /// The identity of this series, based on its labels.
+    ///
+    /// Series with the same labels (in any order) have equal label sets.
+    pub fn label_set(&self) -> LabelSet {
+        LabelSet::new(self.labels.clone())
+    }
+
+    /// Sort labels by name, and the samples and exemplars by timestamp.
+    ///
+    /// Required by the specification.
+    pub fn sort_labels_and_samples(&mut self) {
+        self.labels.sort_by(|a, b| a.name.cmp(&b.name));
+        self.samples.sort_by_key(|a| a.timestamp);
+        self.exemplars.sort_by_key(|a| a.timestamp);
+    }
+
+    /// Keep at most one sample per `interval_millis` wide time bucket.
+    ///
+    /// Samples are sorted by timestamp first, and the last sample of each
+    /// bucket is kept. Buckets are aligned to the Unix epoch. Intervals of
+    /// zero or less leave the samples untouched.
+    pub fn downsample(&mut self, interval_millis: i64) {
+        if interval_millis <= 0 {
+            return;
+        }
+        self.samples.sort_by_key(|s| s.timestamp);
+
+        let bucket = |s: &Sample| s.timestamp.div_euclid(interval_millis);
+        let mut kept: Vec<Sample> = Vec::with_capacity(self.samples.len());
+        for sample in self.samples.drain(..) {
+            match kept.last_mut() {
+                Some(last) if bucket(last) == bucket(&sample) => *last = sample,
+                _ => kept.push(sample),
+            }
+        }
+        self.samples = kept;
+    }
+
+    /// Convert a [`prometheus::proto::MetricFamily`] into time series.
+    ///
+    /// Counters, gauges and untyped metrics produce one series per metric.
+    /// Histograms are expanded into `_bucket` (with an `le` label), `_sum` and
+    /// `_count` series, summaries into quantile (with a `quantile` label),
+    /// `_sum` and `_count` series.
+    ///
+    /// Metrics without an explicit timestamp use the current time.
+    #[cfg(feature = "prometheus")]
+    pub fn from_metric_family(mf: &prometheus::proto::MetricFamily) -> Vec<TimeSeries> {
+        use prometheus::proto::MetricType;
+
+        fn series(
+            name: String,
+            metric: &prometheus::proto::Metric,
+            extra_label: Option<(&str, f64)>,
+            value: f64,
+            timestamp: i64,
+        ) -> TimeSeries {
+            let mut labels = metric
+                .get_label()
+                .iter()
+                .map(|pair| Label {
+                    name: pair.get_name().to_string(),
+                    value: pair.get_value().to_string(),
+                })
+                .collect::<Vec<_>>();
+            labels.push(Label {
+                name: LABEL_NAME.to_string(),
+                value: name,
+            });
+            if let Some((name, bound)) = extra_label {
+                labels.push(Label {
+                    name: name.to_string(),
+                    value: format_sample_value(bound),
+                });
+            }
+
+            let mut series = TimeSeries {
+                labels,
+                samples: vec![Sample { value, timestamp }],
+                exemplars: vec![],
+            };
+            series.sort_labels_and_samples();
+            series
+        }
+
+        let name = mf.get_name();
+        let now = now_millis();
+        let mut all_series = Vec::new();
+
+        for metric in mf.get_metric() {
+            let timestamp = if metric.has_timestamp_ms() {
+                metric.get_timestamp_ms()
+            } else {
+                now
+            };
+
+            match mf.get_field_type() {
+                MetricType::COUNTER => {
+                    let value = metric.get_counter().get_value();
+                    all_series.push(series(name.to_string(), metric, None, value, timestamp));
+                }
+                MetricType::GAUGE => {
+                    let value = metric.get_gauge().get_value();
+                    all_series.push(series(name.to_string(), metric, None, value, timestamp));
+                }
+                MetricType::UNTYPED => {
+                    let value = metric.get_untyped().get_value();
+                    all_series.push(series(name.to_string(), metric, None, value, timestamp));
+                }
+                MetricType::HISTOGRAM => {
+                    let h = metric.get_histogram();
+                    let mut has_inf = false;
+                    for bucket in h.get_bucket() {
+                        let bound = bucket.get_upper_bound();
+                        has_inf |= bound == f64::INFINITY;
+                        all_series.push(series(
+                            format!("{name}_bucket"),
+                            metric,
+                            Some(("le", bound)),
+                            bucket.get_cumulative_count() as f64,
+                            timestamp,
+                        ));
+                    }
+                    // The +Inf bucket is implicit in the protobuf format.
+                    if !has_inf {
+                        all_series.push(series(
+                            format!("{name}_bucket"),
+                            metric,
+                            Some(("le", f64::INFINITY)),
+                            h.get_sample_count() as f64,
+                            timestamp,
+                        ));
+                    }
+                    all_series.push(series(
+                        format!("{name}_sum"),
+                        metric,
+                        None,
+                        h.get_sample_sum(),
+                        timestamp,
+                    ));
+                    all_series.push(series(
+                        format!("{name}_count"),
+                        metric,
+                        None,
+                        h.get_sample_count() as f64,
+                        timestamp,
+                    ));
+                }
+                MetricType::SUMMARY => {
+                    let s = metric.get_summary();
+                    for q in s.get_quantile() {
+                        all_series.push(series(
+                            name.to_string(),
+                            metric,
+                            Some(("quantile", q.get_quantile())),
+                            q.get_value(),
+                            timestamp,
+                        ));
+                    }
+                    all_series.push(series(
+                        format!("{name}_sum"),
+                        metric,
+                        None,
+                        s.get_sample_sum(),
+                        timestamp,
+                    ));
+                    all_series.push(series(
+                        format!("{name}_count"),
+                        metric,
+                        None,
+                        s.get_sample_count() as f64,
+                        timestamp,
+                    ));
+                }
+            }
+        }
+
+        all_series
+    }
+}
+
+/// Current time as milliseconds since the Unix epoch.
+#[cfg(feature = "prometheus")]
+fn now_millis() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+/// Metadata about a metric family.
+///
+/// .proto:
+/// ```protobuf
+/// message MetricMetadata {
+///   MetricType type = 1;
+///   string metric_family_name = 2;
+///   string help = 4;
+///   string unit = 5;
+/// }
+/// ```
+#[derive(prost::Message, Clone, PartialEq)]
+pub struct MetricMetadata {
+    #[prost(enumeration = "MetricMetadataType", tag = "1")]
+    pub r#type: i32,
+    #[prost(string, tag = "2")]
+    pub metric_family_name: String,
+    #[prost(string, tag = "4")]
+    pub help: String,
+    #[prost(string, tag = "5")]
+    pub unit: String,
+}
+
+/// The type of a metric family, as used in [`MetricMetadata`].
+///
+/// .proto:
+/// ```protobuf
+/// enum MetricType {
+///   UNKNOWN        = 0;
+///   COUNTER        = 1;
+///   GAUGE          = 2;
+///   HISTOGRAM      = 3;
+///   GAUGEHISTOGRAM = 4;
+///   SUMMARY        = 5;
+///   INFO           = 6;
+///   STATESET       = 7;
+/// }
+/// ```
+#[derive(prost::Enumeration, Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(i32)]
+pub enum MetricMetadataType {
+    Unknown = 0,
+    Counter = 1,
+    Gauge = 2,
+    Histogram = 3,
+    GaugeHistogram = 4,
+    Summary = 5,
+    Info = 6,
+    StateSet = 7,
+}
+
+/// Check if `name` is a valid label name, matching `[a-zA-Z_][a-zA-Z0-9_]*`.
+fn is_valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+/// A validated metric name.
+///
+/// Metric names must match `[a-zA-Z_:][a-zA-Z0-9_:]*`.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MetricName(String);
+
+impl MetricName {
+    /// Create a new metric name.
+    ///
+    /// Returns an error if the name is not a valid Prometheus metric name.
+    ///
+    /// ```
+    /// use prometheus_remote_write::MetricName;
+    ///
+    /// assert!(MetricName::new("http_requests_total").is_ok());
+    /// assert!(MetricName::new("job:requests:rate5m").is_ok());
+    /// assert!(MetricName::new("1st_metric").is_err());
+    /// ```
+    pub fn new(name: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let mut chars = name.chars();
+        let valid = match chars.next() {
+            Some(c) if c.is_ascii_alphabetic() || c == '_' || c == ':' => {
+                chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+            }
+            _ => false,
+        };
+        if !valid {
+            return Err(format!("invalid metric name: '{name}'").into());
+        }
+        Ok(Self(name.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for MetricName {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for MetricName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<MetricName> for String {
+    fn from(name: MetricName) -> Self {
+        name.0
+    }
+}
+
+/// A label.
+///
+/// .proto:
+/// ```protobuf
+/// message Label {
+///   string name  = 1;
+///   string value = 2;
+/// }
+/// ```
+#[derive(prost::Message, Clone, Hash, PartialEq, Eq)]
+pub struct Label {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+/// A set of labels identifying a time series.
+///
+/// Labels are kept sorted, so two label sets compare equal (and hash the
+/// same) if they contain the same labels, regardless of their original
+/// order. This makes it usable as a map key for grouping samples into
+/// series.
+///
+/// ```
+/// use prometheus_remote_write::{Label, LabelSet};
+///
+/// let label = |name: &str, value: &str| Label {
+///     name: name.to_string(),
+///     value: value.to_string(),
+/// };
+/// let a = LabelSet::new(vec![label("b", "2"), label("a", "1")]);
+/// let b = LabelSet::new(vec![label("a", "1"), label("b", "2")]);
+/// assert_eq!(a, b);
+/// ```
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct LabelSet(Vec<Label>);
+
+impl LabelSet {
+    /// Create a label set, sorting the labels by name and value.
+    pub fn new(mut labels: Vec<Label>) -> Self {
+        labels.sort_by(Self::compare);
+        Self(labels)
+    }
+
+    fn compare(a: &Label, b: &Label) -> std::cmp::Ordering {
+        a.name.cmp(&b.name).then_with(|| a.value.cmp(&b.value))
+    }
+
+    /// Whether `labels`, in any order, are equal to this label set.
+    ///
+    /// Avoids cloning the labels to build a second [`LabelSet`].
+    pub fn matches(&self, labels: &[Label]) -> bool {
+        if labels.len() != self.0.len() {
+            return false;
+        }
+        let mut sorted = labels.iter().collect::<Vec<_>>();
+        sorted.sort_by(|a, b| Self::compare(a, b));
+        sorted.into_iter().eq(self.0.iter())
+    }
+
+    /// The sorted labels.
+    pub fn labels(&self) -> &[Label] {
+        &self.0
+    }
+}
+
+impl From<LabelSet> for Vec<Label> {
+    fn from(set: LabelSet) -> Self {
+        set.0
+    }
+}
+
+/// A typed sample value, used to build requests with
+/// [`WriteRequest::from_metrics`].
+///
+/// The wire format only knows plain [`Sample`]s. The type is kept in the
+/// metadata of the request.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Metric {
+    /// A value that only goes up, except for resets.
+    Counter { value: f64 },
+    /// A value that can go up and down.
+    Gauge { value: f64 },
+    /// A value of unknown type.
+    Untyped { value: f64 },
+}
+
+impl Metric {
+    /// The sample value.
+    pub fn value(&self) -> f64 {
+        match self {
+            Metric::Counter { value } | Metric::Gauge { value } | Metric::Untyped { value } => {
+                *value
+            }
+        }
+    }
+
+    /// The metadata type of the metric.
+    pub fn metadata_type(&self) -> MetricMetadataType {
+        match self {
+            Metric::Counter { .. } => MetricMetadataType::Counter,
+            Metric::Gauge { .. } => MetricMetadataType::Gauge,
+            Metric::Untyped { .. } => MetricMetadataType::Unknown,
+        }
+    }
+}
+
+/// A sample.
+///
+/// .proto:
+/// ```protobuf
+/// message Sample {
+///   double value    = 1;
+///   int64 timestamp = 2;
+/// }
+/// ```
+#[derive(prost::Message, Clone, PartialEq)]
+pub struct Sample {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+    #[prost(int64, tag = "2")]
+    pub timestamp: i64,
+}
+
+impl Sample {
+    /// Create a sample without a timestamp, using timestamp 0.
+    ///
+    /// Some receivers replace a zero timestamp with the time of ingestion.
+    /// This is not part of the remote write specification: Prometheus
+    /// stores the sample at the Unix epoch, which usually means rejecting it
+    /// as too old. Check the documentation of the receiver before relying on
+    /// this.
+    pub fn value_only(value: f64) -> Self {
+        Self {
+            value,
+            timestamp: 0,
+        }
+    }
+
+    /// Create a sample at the given point in time.
+    ///
+    /// Returns an error if the time is before the Unix epoch, or too far in
+    /// the future to be represented as milliseconds in an `i64`.
+    pub fn at(
+        value: f64,
+        time: std::time::SystemTime,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let millis = time
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_err(|_| "sample time is before the Unix epoch".to_string())?
+            .as_millis();
+        let timestamp = i64::try_from(millis)
+            .map_err(|_| format!("sample time is too large: {millis}ms since the Unix epoch"))?;
+        Ok(Self { value, timestamp })
+    }
+
+    /// Create a sample at the given [`chrono::DateTime`].
+    ///
+    /// Returns an error if the time is before the Unix epoch.
+    #[cfg(feature = "chrono")]
+    pub fn at_datetime(
+        value: f64,
+        time: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let timestamp = time.timestamp_millis();
+        if timestamp < 0 {
+            return Err("sample time is before the Unix epoch".into());
+        }
+        Ok(Self { value, timestamp })
+    }
+}
+
+/// An exemplar, linking a sample to a trace.
+///
+/// .proto:
+/// ```protobuf
+/// message Exemplar {
+///   // Optional, can be empty.
+///   repeated Label labels = 1;
+///   double value          = 2;
+///   // timestamp is in ms format
+///   int64 timestamp       = 3;
+/// }
+/// ```
+#[derive(prost::Message, Clone, PartialEq)]
+pub struct Exemplar {
+    #[prost(message, repeated, tag = "1")]
+    pub labels: Vec<Label>,
+    #[prost(double, tag = "2")]
+    pub value: f64,
+    #[prost(int64, tag = "3")]
+    pub timestamp: i64,
+}
+
+/// OpenTelemetry metrics messages (OTLP), as accepted by
+/// [`WriteRequest::from_otlp`].
+///
+/// These follow `opentelemetry/proto/metrics/v1/metrics.proto` and its
+/// imports, but only contain the fields needed for the conversion. Other
+/// fields are skipped when decoding, so the messages can be decoded from
+/// any OTLP metrics payload with [`prost::Message::decode`].
+#[cfg(feature = "otlp")]
+pub mod otlp {
+    /// Bit of the data point `flags` marking a point without a value.
+    pub const FLAG_NO_RECORDED_VALUE: u32 = 1;
+
+    #[derive(prost::Message, Clone, PartialEq)]
+    pub struct MetricsData {
+        #[prost(message, repeated, tag = "1")]
+        pub resource_metrics: Vec<ResourceMetrics>,
+    }
+
+    #[derive(prost::Message, Clone, PartialEq)]
+    pub struct ResourceMetrics {
+        #[prost(message, optional, tag = "1")]
+        pub resource: Option<Resource>,
+        #[prost(message, repeated, tag = "2")]
+        pub scope_metrics: Vec<ScopeMetrics>,
+    }
+
+    #[derive(prost::Message, Clone, PartialEq)]
+    pub struct Resource {
+        #[prost(message, repeated, tag = "1")]
+        pub attributes: Vec<KeyValue>,
+    }
+
+    #[derive(prost::Message, Clone, PartialEq)]
+    pub struct ScopeMetrics {
+        #[prost(message, repeated, tag = "2")]
+        pub metrics: Vec<Metric>,
+    }
+
+    #[derive(prost::Message, Clone, PartialEq)]
+    pub struct Metric {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(string, tag = "2")]
+        pub description: String,
+        #[prost(string, tag = "3")]
+        pub unit: String,
+        #[prost(oneof = "metric::Data", tags = "5, 7, 9, 10, 11")]
+        pub data: Option<metric::Data>,
+    }
+
+    pub mod metric {
+        #[derive(prost::Oneof, Clone, PartialEq)]
+        pub enum Data {
+            #[prost(message, tag = "5")]
+            Gauge(super::Gauge),
+            #[prost(message, tag = "7")]
+            Sum(super::Sum),
+            #[prost(message, tag = "9")]
+            Histogram(super::Histogram),
+            /// Not supported, the content is skipped.
+            #[prost(message, tag = "10")]
+            ExponentialHistogram(super::Unsupported),
+            /// Not supported, the content is skipped.
+            #[prost(message, tag = "11")]
+            Summary(super::Unsupported),
+        }
+    }
+
+    #[derive(prost::Message, Clone, PartialEq)]
+    pub struct Gauge {
+        #[prost(message, repeated, tag = "1")]
+        pub data_points: Vec<NumberDataPoint>,
+    }
+
+    #[derive(prost::Message, Clone, PartialEq)]
+    pub struct Sum {
+        #[prost(message, repeated, tag = "1")]
+        pub data_points: Vec<NumberDataPoint>,
+        #[prost(enumeration = "AggregationTemporality", tag = "2")]
+        pub aggregation_temporality: i32,
+        #[prost(bool, tag = "3")]
+        pub is_monotonic: bool,
+    }
+
+    #[derive(prost::Message, Clone, PartialEq)]
+    pub struct Histogram {
+        #[prost(message, repeated, tag = "1")]
+        pub data_points: Vec<HistogramDataPoint>,
+        #[prost(enumeration = "AggregationTemporality", tag = "2")]
+        pub aggregation_temporality: i32,
+    }
+
+    /// A metric type that can not be converted.
+    #[derive(prost::Message, Clone, PartialEq)]
+    pub struct Unsupported {}
+
+    #[derive(prost::Enumeration, Clone, Copy, Debug, Hash, PartialEq, Eq)]
+    #[repr(i32)]
+    pub enum AggregationTemporality {
+        Unspecified = 0,
+        Delta = 1,
+        Cumulative = 2,
+    }
+
+    #[derive(prost::Message, Clone, PartialEq)]
+    pub struct NumberDataPoint {
+        #[prost(message, repeated, tag = "7")]
+        pub attributes: Vec<KeyValue>,
+        #[prost(fixed64, tag = "3")]
+        pub time_unix_nano: u64,
+        #[prost(oneof = "number_data_point::Value", tags = "4, 6")]
+        pub value: Option<number_data_point::Value>,
+        #[prost(uint32, tag = "8")]
+        pub flags: u32,
+    }
+
+    pub mod number_data_point {
+        #[derive(prost::Oneof, Clone, PartialEq)]
+        pub enum Value {
+            #[prost(double, tag = "4")]
+            AsDouble(f64),
+            #[prost(sfixed64, tag = "6")]
+            AsInt(i64),
+        }
+    }
+
+    #[derive(prost::Message, Clone, PartialEq)]
+    pub struct HistogramDataPoint {
+        #[prost(message, repeated, tag = "9")]
+        pub attributes: Vec<KeyValue>,
+        #[prost(fixed64, tag = "3")]
+        pub time_unix_nano: u64,
+        #[prost(fixed64, tag = "4")]
+        pub count: u64,
+        #[prost(double, optional, tag = "5")]
+        pub sum: Option<f64>,
+        #[prost(fixed64, repeated, tag = "6")]
+        pub bucket_counts: Vec<u64>,
+        #[prost(double, repeated, tag = "7")]
+        pub explicit_bounds: Vec<f64>,
+        #[prost(uint32, tag = "10")]
+        pub flags: u32,
+    }
+
+    #[derive(prost::Message, Clone, PartialEq)]
+    pub struct KeyValue {
+        #[prost(string, tag = "1")]
+        pub key: String,
+        #[prost(message, optional, tag = "2")]
+        pub value: Option<AnyValue>,
+    }
+
+    /// An attribute value. Arrays, key-value lists and bytes are skipped.
+    #[derive(prost::Message, Clone, PartialEq)]
+    pub struct AnyValue {
+        #[prost(oneof = "any_value::Value", tags = "1, 2, 3, 4")]
+        pub value: Option<any_value::Value>,
+    }
+
+    pub mod any_value {
+        #[derive(prost::Oneof, Clone, PartialEq)]
+        pub enum Value {
+            #[prost(string, tag = "1")]
+            StringValue(String),
+            #[prost(bool, tag = "2")]
+            BoolValue(bool),
+            #[prost(int64, tag = "3")]
+            IntValue(i64),
+            #[prost(double, tag = "4")]
+            DoubleValue(f64),
+        }
+    }
+}
+
+#[cfg(all(feature = "parse", feature = "compression"))]
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_metric_name_valid() {
+        for name in [
+            "a",
+            "_",
+            "up",
+            "http_requests_total",
+            "A1_b2",
+            ":x",
+            "job:rate:5m",
+        ] {
+            assert_eq!(MetricName::new(name).unwrap().as_str(), name);
+        }
+    }
+
+    #[test]
+    fn test_metric_name_invalid() {
+        for name in ["", "1abc", "a-b", "a b", "a.b", "é", "x{}"] {
+            let err = MetricName::new(name).unwrap_err();
+            assert!(err.to_string().contains("invalid metric name"), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_time_series_new() {
+        let series = TimeSeries::new(
+            MetricName::new("job:up").unwrap(),
+            vec![
+                Label {
+                    name: "z".to_string(),
+                    value: "1".to_string(),
+                },
+                Label {
+                    name: LABEL_NAME.to_string(),
+                    value: "ignored".to_string(),
+                },
+            ],
+            vec![
+                Sample {
+                    value: 2.0,
+                    timestamp: 2,
+                },
+                Sample {
+                    value: 1.0,
+                    timestamp: 1,
+                },
+            ],
+        );
+        assert_eq!(
+            series.labels,
+            vec![
+                Label {
+                    name: LABEL_NAME.to_string(),
+                    value: "job:up".to_string(),
+                },
+                Label {
+                    name: "z".to_string(),
+                    value: "1".to_string(),
+                },
+            ]
+        );
+        assert_eq!(series.samples[0].timestamp, 1);
+    }
+    #[test]
+    fn test_name() {
+        let input = r#"
+# TYPE mycounter counter
+# TYPE mygauge gauge
+
+mygauge 100 100
+http_requests_total{method="post",code="200"} 1027 1395066363000
+mycounter 100 100
+alpha 10 1000
+http_requests_total{method="post",code="200"} 50 1000
+    "#;
+
+        let req = WriteRequest::from_text_format(input.to_string()).unwrap();
+
+        assert_eq!(
+            req,
+            WriteRequest {
+                timeseries: vec![
+                    TimeSeries {
+                        labels: vec![Label {
+                            name: LABEL_NAME.to_string(),
+                            value: "alpha".to_string()
+                        },],
+                        samples: vec![Sample {
+                            value: 10.0,
+                            timestamp: 1000,
+                        },],
+                        exemplars: vec![],
+                    },
+                    TimeSeries {
+                        labels: vec![
+                            Label {
+                                name: LABEL_NAME.to_string(),
+                                value: "http_requests_total".to_string()
+                            },
+                            Label {
+                                name: "code".to_string(),
+                                value: "200".to_string()
+                            },
+                            Label {
+                                name: "method".to_string(),
                                 value: "post".to_string()
                             },
                         ],
@@ -278,33 +3772,3431 @@ http_requests_total{method="post",code="200"} 50 1000
                                 value: 1027.0,
                                 timestamp: 1395066363000
                             },
-                        ]
+                        ],
+                        exemplars: vec![],
+                    },
+                    TimeSeries {
+                        labels: vec![Label {
+                            name: LABEL_NAME.to_string(),
+                            value: "mycounter".to_string()
+                        },],
+                        samples: vec![Sample {
+                            value: 100.0,
+                            timestamp: 100,
+                        }],
+                        exemplars: vec![],
+                    },
+                    TimeSeries {
+                        labels: vec![Label {
+                            name: LABEL_NAME.to_string(),
+                            value: "mygauge".to_string()
+                        },],
+                        samples: vec![Sample {
+                            value: 100.0,
+                            timestamp: 100,
+                        }],
+                        exemplars: vec![],
+                    },
+                ],
+                metadata: vec![],
+            }
+        );
+
+        let _x = req.clone().encode_proto3();
+        let _y = req.encode_compressed();
+    }
+
+    #[test]
+    fn test_from_samples_groups_by_label_set() {
+        let req = WriteRequest::from_samples(vec![
+            (
+                "requests".to_string(),
+                vec![("method".to_string(), "GET".to_string())],
+                2.0,
+                2000,
+            ),
+            (
+                "requests".to_string(),
+                vec![("method".to_string(), "GET".to_string())],
+                1.0,
+                1000,
+            ),
+        ]);
+
+        assert_eq!(
+            req,
+            WriteRequest {
+                timeseries: vec![TimeSeries {
+                    labels: vec![
+                        Label {
+                            name: LABEL_NAME.to_string(),
+                            value: "requests".to_string(),
+                        },
+                        Label {
+                            name: "method".to_string(),
+                            value: "GET".to_string(),
+                        },
+                    ],
+                    samples: vec![
+                        Sample {
+                            value: 1.0,
+                            timestamp: 1000,
+                        },
+                        Sample {
+                            value: 2.0,
+                            timestamp: 2000,
+                        },
+                    ],
+                    exemplars: vec![],
+                }],
+                metadata: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn test_from_text_format_interleaved_samples() {
+        let mut input = String::new();
+        let mut expected = std::collections::BTreeMap::<(String, String), Vec<Sample>>::new();
+        for timestamp in (1..=50).rev() {
+            for host in 0..7 {
+                let name = format!("metric_{}", host % 3);
+                let value = (timestamp * host) as f64;
+                input.push_str(&format!(
+                    "{name}{{instance=\"host{host}\",job=\"x\"}} {value} {timestamp}\n"
+                ));
+                expected
+                    .entry((name, format!("host{host}")))
+                    .or_default()
+                    .insert(0, Sample { value, timestamp });
+            }
+        }
+
+        let req = WriteRequest::from_text_format(input).unwrap();
+        let expected = expected
+            .into_iter()
+            .map(|((name, host), samples)| TimeSeries {
+                labels: vec![
+                    Label {
+                        name: LABEL_NAME.to_string(),
+                        value: name,
+                    },
+                    Label {
+                        name: "instance".to_string(),
+                        value: host,
+                    },
+                    Label {
+                        name: "job".to_string(),
+                        value: "x".to_string(),
+                    },
+                ],
+                samples,
+                exemplars: vec![],
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(req.timeseries, expected);
+        for series in &req.timeseries {
+            assert_eq!(series.samples.capacity(), series.samples.len());
+        }
+    }
+
+    #[test]
+    fn test_from_text_format_concatenated_scrapes() {
+        let input = r#"# HELP http_requests_total Requests.
+# TYPE http_requests_total counter
+http_requests_total{code="200",method="GET"} 10 1000
+http_requests_total{code="500",method="GET"} 1 1000
+# HELP up Up.
+# TYPE up gauge
+up 1 1000
+# HELP http_requests_total Requests.
+# TYPE http_requests_total counter
+http_requests_total{method="POST",code="200"} 4 2000
+http_requests_total{method="GET",code="200"} 12 2000
+# HELP up Up.
+# TYPE up gauge
+up 1 2000
+http_requests_total{code="500",method="GET"} 2 2000
+"#;
+
+        let req = WriteRequest::from_text_format(input.to_string()).unwrap();
+        let series = req
+            .timeseries
+            .iter()
+            .map(|series| {
+                let labels = series
+                    .labels
+                    .iter()
+                    .map(|l| format!("{}={}", l.name, l.value))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                let samples = series
+                    .samples
+                    .iter()
+                    .map(|s| (s.value, s.timestamp))
+                    .collect::<Vec<_>>();
+                (labels, samples)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            series,
+            vec![
+                (
+                    "__name__=http_requests_total,code=200,method=GET".to_string(),
+                    vec![(10.0, 1000), (12.0, 2000)],
+                ),
+                (
+                    "__name__=http_requests_total,code=200,method=POST".to_string(),
+                    vec![(4.0, 2000)],
+                ),
+                (
+                    "__name__=http_requests_total,code=500,method=GET".to_string(),
+                    vec![(1.0, 1000), (2.0, 2000)],
+                ),
+                ("__name__=up".to_string(), vec![(1.0, 1000), (1.0, 2000)]),
+            ]
+        );
+        req.validate().unwrap();
+
+        let families = WriteRequest::from_text_format_with_metadata(input.to_string())
+            .unwrap()
+            .metadata
+            .into_iter()
+            .map(|m| m.metric_family_name)
+            .collect::<Vec<_>>();
+        assert_eq!(families, vec!["http_requests_total", "up"]);
+    }
+
+    #[test]
+    fn test_from_text_format_empty_input() {
+        for input in ["", "  \n\t\n", "# HELP x Help.\n# TYPE x gauge\n"] {
+            let req = WriteRequest::from_text_format(input.to_string()).unwrap();
+            assert!(req.timeseries.is_empty(), "{input:?}");
+            assert!(req.metadata.is_empty(), "{input:?}");
+        }
+    }
+
+    #[test]
+    fn test_from_text_format_partial() {
+        let input = r#"# TYPE requests_total counter
+requests_total{code="200"} 10 1000
+# TYPE latency histogram
+latency_bucket{le="1"} 1 1000
+latency_bucket{le="+Inf"} 2 1000
+latency_sum 3 1000
+latency_count 2 1000
+temperature 21.5 1000
+temperature{room="b"} warm 1000
+{broken
+# TYPE rpc summary
+rpc{quantile="0.5"} 1 1000
+"#;
+        let (req, skipped) = WriteRequest::from_text_format_partial(input.to_string());
+
+        let expected = WriteRequest::from_text_format(
+            "requests_total{code=\"200\"} 10 1000\ntemperature 21.5 1000\n".to_string(),
+        )
+        .unwrap();
+        assert_eq!(req, expected);
+
+        assert_eq!(
+            skipped,
+            vec![
+                SkippedFamily {
+                    name: "latency".to_string(),
+                    line: Some(4),
+                    reason: SkipReason::UnsupportedType(MetricMetadataType::Histogram),
+                },
+                SkippedFamily {
+                    name: "temperature".to_string(),
+                    line: Some(9),
+                    reason: SkipReason::InvalidValue("warm".to_string()),
+                },
+                SkippedFamily {
+                    name: String::new(),
+                    line: Some(10),
+                    reason: SkipReason::MalformedLine,
+                },
+                SkippedFamily {
+                    name: "rpc".to_string(),
+                    line: Some(12),
+                    reason: SkipReason::UnsupportedType(MetricMetadataType::Summary),
+                },
+            ]
+        );
+        assert_eq!(
+            skipped[0].to_string(),
+            "line 4: 'latency': histogram not supported yet"
+        );
+    }
+
+    #[test]
+    fn test_from_text_format_preserve_order() {
+        let input = "b{x=\"1\"} 1 3000\na 2 2000\nb{x=\"1\"} 3 1000\na 4 1000\n".to_string();
+
+        let req = WriteRequest::from_text_format_preserve_order(input.clone()).unwrap();
+        let order = req
+            .iter_samples()
+            .map(|(labels, sample)| (labels[0].value.as_str(), sample.timestamp))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            order,
+            vec![("b", 3000), ("b", 1000), ("a", 2000), ("a", 1000)]
+        );
+        assert!(req.check_sorted().is_err());
+
+        let req = req.sorted();
+        let timestamps = req
+            .timeseries
+            .iter()
+            .map(|s| s.samples.iter().map(|s| s.timestamp).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(timestamps, vec![vec![1000, 3000], vec![1000, 2000]]);
+
+        // Only the order differs from the regular parser.
+        let mut req = req;
+        req.timeseries.sort_by(compare_series);
+        assert_eq!(req, WriteRequest::from_text_format(input).unwrap());
+    }
+
+    #[test]
+    fn test_from_text_format_label_set_no_collision() {
+        // These used to map to the same grouping key, as label pairs were
+        // concatenated without a separator.
+        let input = "x{a=\"bc\",d=\"e\"} 1 1\nx{a=\"b\",cd=\"e\"} 2 1\n";
+        let req = WriteRequest::from_text_format(input.to_string()).unwrap();
+        assert_eq!(req.timeseries.len(), 2);
+
+        let sets = req
+            .timeseries
+            .iter()
+            .map(TimeSeries::label_set)
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(sets.len(), 2);
+    }
+
+    #[test]
+    fn test_from_samples_label_separator_no_collision() {
+        // With a string key of concatenated `name=value` pairs, both samples
+        // produced `__name__=xa=b=c`.
+        let req = WriteRequest::from_samples(vec![
+            (
+                "x".to_string(),
+                vec![("a".to_string(), "b=c".to_string())],
+                1.0,
+                1,
+            ),
+            (
+                "x".to_string(),
+                vec![("a=b".to_string(), "c".to_string())],
+                2.0,
+                1,
+            ),
+        ]);
+        assert_eq!(req.timeseries.len(), 2);
+        assert!(req.timeseries.iter().all(|s| s.samples.len() == 1));
+    }
+
+    #[test]
+    fn test_sanitize_names_legacy() {
+        let label = |name: &str, value: &str| Label {
+            name: name.to_string(),
+            value: value.to_string(),
+        };
+        let mut req = WriteRequest {
+            timeseries: vec![TimeSeries {
+                labels: vec![
+                    label(LABEL_NAME, "http.server.duration"),
+                    label("service name", "my service"),
+                    label("1st", "a.b"),
+                    label("job:x", "1"),
+                    label("ünits", "ms"),
+                ],
+                samples: vec![],
+                exemplars: vec![],
+            }],
+            metadata: vec![MetricMetadata {
+                metric_family_name: "2xx responses:rate".to_string(),
+                ..Default::default()
+            }],
+        };
+        req.sanitize_names_legacy();
+
+        assert_eq!(
+            req.timeseries[0].labels,
+            vec![
+                label(LABEL_NAME, "http_server_duration"),
+                label("service_name", "my service"),
+                label("_1st", "a.b"),
+                label("job_x", "1"),
+                label("_nits", "ms"),
+            ]
+        );
+        assert_eq!(req.metadata[0].metric_family_name, "_2xx_responses:rate");
+
+        let mut valid = WriteRequest::from_samples(vec![(
+            "job:requests:rate5m".to_string(),
+            vec![("_path".to_string(), "/".to_string())],
+            1.0,
+            1,
+        )]);
+        let original = valid.clone();
+        valid.sanitize_names_legacy();
+        assert_eq!(valid, original);
+    }
+
+    #[test]
+    fn test_push_sample() {
+        let label = |name: &str, value: &str| Label {
+            name: name.to_string(),
+            value: value.to_string(),
+        };
+        let sample = |value, timestamp| Sample { value, timestamp };
+
+        let mut req = WriteRequest::default();
+        req.push_sample(
+            vec![label(LABEL_NAME, "x"), label("a", "1")],
+            sample(1.0, 1),
+        );
+        req.push_sample(
+            vec![label("a", "1"), label(LABEL_NAME, "x")],
+            sample(2.0, 2),
+        );
+        req.push_sample(
+            vec![label(LABEL_NAME, "x"), label("a", "2")],
+            sample(3.0, 1),
+        );
+
+        assert_eq!(
+            req.timeseries,
+            vec![
+                TimeSeries {
+                    labels: vec![label(LABEL_NAME, "x"), label("a", "1")],
+                    samples: vec![sample(1.0, 1), sample(2.0, 2)],
+                    exemplars: vec![],
+                },
+                TimeSeries {
+                    labels: vec![label(LABEL_NAME, "x"), label("a", "2")],
+                    samples: vec![sample(3.0, 1)],
+                    exemplars: vec![],
+                },
+            ]
+        );
+        assert!(LabelSet::new(vec![label("a", "1")]).matches(&[label("a", "1")]));
+        assert!(!LabelSet::new(vec![label("a", "1")]).matches(&[label("a", "2")]));
+    }
+
+    #[test]
+    fn test_label_set_ignores_order() {
+        let label = |name: &str, value: &str| Label {
+            name: name.to_string(),
+            value: value.to_string(),
+        };
+        let series = TimeSeries {
+            labels: vec![label("z", "1"), label(LABEL_NAME, "x"), label("a", "2")],
+            samples: vec![],
+            exemplars: vec![],
+        };
+        let set = series.label_set();
+        assert_eq!(
+            set.labels(),
+            &[label(LABEL_NAME, "x"), label("a", "2"), label("z", "1")]
+        );
+        assert_eq!(
+            set,
+            LabelSet::new(vec![
+                label("a", "2"),
+                label("z", "1"),
+                label(LABEL_NAME, "x")
+            ])
+        );
+        assert_ne!(
+            set,
+            LabelSet::new(vec![label(LABEL_NAME, "x"), label("a", "2")])
+        );
+    }
+
+    #[test]
+    fn test_from_text_format_deterministic_series_order() {
+        let input = r#"
+x{b="1"} 1 1
+x{a="2"} 1 1
+x{a="1"} 1 1
+y 1 1
+x 1 1
+"#;
+
+        let first = WriteRequest::from_text_format(input.to_string()).unwrap();
+        let order = first
+            .timeseries
+            .iter()
+            .map(|s| {
+                s.labels
+                    .iter()
+                    .map(|l| format!("{}={}", l.name, l.value))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            order,
+            vec![
+                "__name__=x",
+                "__name__=x,a=1",
+                "__name__=x,a=2",
+                "__name__=x,b=1",
+                "__name__=y",
+            ]
+        );
+
+        let encoded = first.encode_proto3();
+        for _ in 0..20 {
+            let req = WriteRequest::from_text_format(input.to_string()).unwrap();
+            assert_eq!(req.encode_proto3(), encoded);
+        }
+    }
+
+    #[test]
+    fn test_encode_proto3_unsorted() {
+        let req = WriteRequest {
+            timeseries: vec![TimeSeries {
+                labels: vec![
+                    Label {
+                        name: "z".to_string(),
+                        value: "1".to_string(),
+                    },
+                    Label {
+                        name: LABEL_NAME.to_string(),
+                        value: "x".to_string(),
+                    },
+                ],
+                samples: vec![
+                    Sample {
+                        value: 2.0,
+                        timestamp: 2,
+                    },
+                    Sample {
+                        value: 1.0,
+                        timestamp: 1,
+                    },
+                ],
+                exemplars: vec![],
+            }],
+            metadata: vec![],
+        };
+
+        let sorted = req.clone().encode_proto3();
+        let unsorted = req.clone().encode_proto3_unsorted();
+        assert_ne!(sorted, unsorted);
+        assert_eq!(unsorted, prost::Message::encode_to_vec(&req));
+        assert_eq!(sorted, req.sorted().encode_proto3_unsorted());
+    }
+
+    #[test]
+    fn test_encode_proto3_single_sample_fast_path() {
+        let label = |name: &str, value: &str| Label {
+            name: name.to_string(),
+            value: value.to_string(),
+        };
+        let series = |labels: Vec<Label>, samples: Vec<Sample>| WriteRequest {
+            timeseries: vec![TimeSeries {
+                labels,
+                samples,
+                exemplars: vec![],
+            }],
+            metadata: vec![],
+        };
+        let sample = |timestamp| Sample {
+            value: 1.0,
+            timestamp,
+        };
+
+        let cases = vec![
+            series(
+                vec![label(LABEL_NAME, "x"), label("a", "1")],
+                vec![sample(1)],
+            ),
+            series(
+                vec![label("b", "2"), label(LABEL_NAME, "x"), label("a", "1")],
+                vec![sample(1)],
+            ),
+            series(vec![label("a", "1")], vec![]),
+            series(vec![label(LABEL_NAME, "x")], vec![sample(2), sample(1)]),
+        ];
+        for req in cases {
+            let general = req.clone().sorted().encode_proto3_unsorted();
+            assert_eq!(req.encode_proto3(), general);
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_encode_zstd_round_trip() {
+        let req = WriteRequest::from_samples(
+            (0..100).map(|i| ("x".to_string(), vec![], i as f64, i * 1000)),
+        );
+        let proto = req.clone().encode_proto3();
+
+        for level in [1, 3, 19] {
+            let body = req.clone().encode_zstd(level).unwrap();
+            assert!(body.len() < proto.len());
+            let decoded = zstd::bulk::decompress(&body, proto.len()).unwrap();
+            assert_eq!(decoded, proto);
+            assert_eq!(WriteRequest::try_from(decoded.as_slice()).unwrap(), req);
+        }
+    }
+
+    #[cfg(all(feature = "zstd", feature = "http"))]
+    #[test]
+    fn test_build_http_request_zstd() {
+        let req = WriteRequest::from_samples(vec![("a".to_string(), vec![], 1.0, 1)]);
+        let endpoint = url::Url::parse("http://localhost/api/v1/write").unwrap();
+        let body = req.encode_zstd(3).unwrap();
+        let http_req = WriteRequest::build_http_request_from_bytes(
+            body.clone(),
+            &endpoint,
+            "test",
+            BodyEncoding::Zstd,
+        )
+        .unwrap();
+        assert_eq!(http_req.headers()[http::header::CONTENT_ENCODING], "zstd");
+        assert_eq!(http_req.body(), &body);
+    }
+
+    #[test]
+    fn test_compressed_len() {
+        let sorted = WriteRequest::from_samples(
+            (0..200).map(|i| (format!("m{}", i % 7), vec![], i as f64, i)),
+        );
+        let mut unsorted = sorted.clone();
+        unsorted.timeseries[0].samples.reverse();
+        unsorted.timeseries[1].labels.push(Label {
+            name: "a".to_string(),
+            value: "b".to_string(),
+        });
+        unsorted.timeseries[1].labels.reverse();
+
+        // Larger than the retained buffer, followed by a small request.
+        let large = WriteRequest::from_samples((0..50_000).map(|i| {
+            (
+                "m".to_string(),
+                vec![("i".to_string(), i.to_string())],
+                i as f64,
+                i,
+            )
+        }));
+        assert!(large.clone().encode_proto3().len() > MAX_RETAINED_COMPRESS_BUFFER);
+
+        for req in [WriteRequest::default(), large, sorted, unsorted] {
+            assert_eq!(
+                req.compressed_len().unwrap(),
+                req.clone().encode_compressed().unwrap().len()
+            );
+        }
+    }
+
+    #[test]
+    fn test_into_batches_by_compressed_size() {
+        // Pseudo-random label values, so the body does not compress too well.
+        let mut state = 0x2545_f491_u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let mut req = WriteRequest::from_samples((0..3000).map(|i| {
+            let labels = vec![("id".to_string(), format!("{:x}{:x}", next(), next()))];
+            (format!("metric_{}", i % 13), labels, i as f64, i)
+        }));
+        req.metadata = vec![MetricMetadata {
+            metric_family_name: "metric_1".to_string(),
+            help: "help".to_string(),
+            ..Default::default()
+        }];
+
+        for max in [300, 5_000, 100_000] {
+            let batches = req.clone().into_batches_by_compressed_size(max).unwrap();
+            assert!(batches.len() > 1);
+
+            let sizes = batches
+                .iter()
+                .map(|b| b.clone().encode_compressed().unwrap().len())
+                .collect::<Vec<_>>();
+            assert!(sizes.iter().all(|size| *size <= max), "{sizes:?}");
+
+            // Packing is greedy: the next series would not have fit.
+            for pair in batches.windows(2) {
+                let mut extended = pair[0].clone();
+                match pair[1].timeseries.first() {
+                    Some(series) => extended.timeseries.push(series.clone()),
+                    None => extended.metadata.push(pair[1].metadata[0].clone()),
+                }
+                assert!(extended.encode_compressed().unwrap().len() > max);
+            }
+
+            let mut merged = WriteRequest::default();
+            for batch in batches {
+                merged.timeseries.extend(batch.timeseries);
+                merged.metadata.extend(batch.metadata);
+            }
+            assert_eq!(merged, req);
+        }
+
+        let err = req.into_batches_by_compressed_size(20).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("does not fit into 20 compressed bytes"));
+    }
+
+    #[test]
+    fn test_clear_retains_capacity() {
+        let mut req =
+            WriteRequest::from_samples((0..10).map(|i| (format!("metric_{i}"), vec![], 1.0, i)));
+        let capacity = req.timeseries.capacity();
+        assert!(capacity >= 10);
+
+        req.clear();
+        assert!(req.timeseries.is_empty());
+        assert_eq!(req.timeseries.capacity(), capacity);
+    }
+
+    #[test]
+    fn test_clear_samples_retains_series() {
+        let mut req =
+            WriteRequest::from_samples((0..10).map(|i| ("x".to_string(), vec![], 1.0, i)));
+        let capacity = req.timeseries[0].samples.capacity();
+
+        req.clear_samples();
+        assert_eq!(req.timeseries.len(), 1);
+        assert_eq!(req.timeseries[0].labels.len(), 1);
+        assert!(req.timeseries[0].samples.is_empty());
+        assert_eq!(req.timeseries[0].samples.capacity(), capacity);
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_build_http_request_compression_threshold() {
+        let url = url::Url::parse("http://localhost/api/v1/write").unwrap();
+        let policy = CompressionPolicy::Threshold(100);
+
+        let tiny = WriteRequest::from_samples(vec![("x".to_string(), vec![], 1.0, 1)]);
+        let req = tiny
+            .clone()
+            .build_http_request_with_compression(&url, "test", policy)
+            .unwrap();
+        assert_eq!(req.headers()[http::header::CONTENT_ENCODING], "identity");
+        assert_eq!(req.body(), &tiny.encode_proto3());
+
+        let large =
+            WriteRequest::from_samples((0..100).map(|i| (format!("metric_{i}"), vec![], 1.0, i)));
+        let req = large
+            .clone()
+            .build_http_request_with_compression(&url, "test", policy)
+            .unwrap();
+        assert_eq!(req.headers()[http::header::CONTENT_ENCODING], "snappy");
+        assert_eq!(req.body(), &large.encode_compressed().unwrap());
+    }
+
+    #[test]
+    fn test_byte_conversions_round_trip() {
+        let req = WriteRequest::from_samples(vec![
+            (
+                "x".to_string(),
+                vec![("a".to_string(), "b".to_string())],
+                1.5,
+                10,
+            ),
+            ("y".to_string(), vec![], 2.0, 20),
+        ]);
+
+        let bytes: Vec<u8> = req.clone().into();
+        let decoded = WriteRequest::try_from(bytes.as_slice()).unwrap();
+        assert_eq!(decoded, req);
+
+        assert!(WriteRequest::try_from(&[0xff, 0xff][..]).is_err());
+    }
+
+    #[test]
+    fn test_drop_samples_before() {
+        let mut req = WriteRequest::from_samples(vec![
+            ("old".to_string(), vec![], 1.0, 100),
+            ("mixed".to_string(), vec![], 1.0, 100),
+            ("mixed".to_string(), vec![], 2.0, 200),
+            ("new".to_string(), vec![], 1.0, 300),
+        ]);
+
+        req.drop_samples_before(200);
+
+        assert_eq!(
+            req,
+            WriteRequest::from_samples(vec![
+                ("mixed".to_string(), vec![], 2.0, 200),
+                ("new".to_string(), vec![], 1.0, 300),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_clamp_future_samples() {
+        let mut req = WriteRequest::from_samples(vec![
+            ("x".to_string(), vec![], 1.0, 100),
+            ("x".to_string(), vec![], 2.0, 5000),
+            ("y".to_string(), vec![], 1.0, 100),
+            ("y".to_string(), vec![], 2.0, 1000),
+            ("y".to_string(), vec![], 3.0, 3000),
+            ("y".to_string(), vec![], 4.0, 2000),
+            ("z".to_string(), vec![], 1.0, 100),
+            ("z".to_string(), vec![], 2.0, 1000),
+        ]);
+
+        req.clamp_future_samples(1000);
+
+        assert_eq!(
+            req,
+            WriteRequest::from_samples(vec![
+                ("x".to_string(), vec![], 1.0, 100),
+                ("x".to_string(), vec![], 2.0, 1000),
+                ("y".to_string(), vec![], 1.0, 100),
+                ("y".to_string(), vec![], 3.0, 1000),
+                ("z".to_string(), vec![], 1.0, 100),
+                ("z".to_string(), vec![], 2.0, 1000),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_histogram_buckets_keep_numeric_order() {
+        let bucket = |le: &str, value: f64| {
+            (
+                "latency_bucket".to_string(),
+                vec![
+                    ("le".to_string(), le.to_string()),
+                    ("path".to_string(), "/".to_string()),
+                ],
+                value,
+                1000,
+            )
+        };
+        let req = WriteRequest::from_samples(vec![
+            bucket("+Inf", 4.0),
+            bucket("10", 3.0),
+            bucket("0.1", 1.0),
+            bucket("1", 2.0),
+        ]);
+
+        let le_values = req
+            .timeseries
+            .iter()
+            .map(|s| {
+                s.labels
+                    .iter()
+                    .find(|l| l.name == "le")
+                    .unwrap()
+                    .value
+                    .as_str()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(le_values, vec!["0.1", "1", "10", "+Inf"]);
+
+        // Labels within each series are still sorted lexically by name.
+        for series in &req.timeseries {
+            let names = series
+                .labels
+                .iter()
+                .map(|l| l.name.as_str())
+                .collect::<Vec<_>>();
+            assert_eq!(names, vec![LABEL_NAME, "le", "path"]);
+        }
+
+        assert_eq!(
+            req.to_text_format(),
+            r#"latency_bucket{le="0.1",path="/"} 1 1000
+latency_bucket{le="1",path="/"} 2 1000
+latency_bucket{le="10",path="/"} 3 1000
+latency_bucket{le="+Inf",path="/"} 4 1000
+"#
+        );
+    }
+
+    #[test]
+    fn test_retain_series() {
+        let mut req = WriteRequest::from_samples(vec![
+            ("http_requests_total".to_string(), vec![], 1.0, 1),
+            ("http_errors_total".to_string(), vec![], 2.0, 1),
+            ("process_cpu_seconds".to_string(), vec![], 3.0, 1),
+        ]);
+        req.metadata = vec![
+            MetricMetadata {
+                metric_family_name: "http_errors_total".to_string(),
+                ..Default::default()
+            },
+            MetricMetadata {
+                metric_family_name: "process_cpu_seconds".to_string(),
+                ..Default::default()
+            },
+        ];
+        let has_prefix = |prefix: &'static str| {
+            move |series: &TimeSeries| {
+                series
+                    .labels
+                    .iter()
+                    .any(|l| l.name == LABEL_NAME && l.value.starts_with(prefix))
+            }
+        };
+
+        req.retain_series(has_prefix("http_"));
+        let names = req
+            .timeseries
+            .iter()
+            .map(|s| s.labels[0].value.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["http_errors_total", "http_requests_total"]);
+        assert_eq!(req.metadata.len(), 1);
+        assert_eq!(req.metadata[0].metric_family_name, "http_errors_total");
+
+        req.retain_series(has_prefix("node_"));
+        assert_eq!(req, WriteRequest::default());
+
+        // Histogram series are named after their family with a suffix, and
+        // families without series keep their metadata.
+        let bucket = |le: &str| vec![("le".to_string(), le.to_string())];
+        let mut req = WriteRequest::from_samples(vec![
+            ("latency_bucket".to_string(), bucket("1"), 1.0, 1000),
+            ("latency_bucket".to_string(), bucket("+Inf"), 2.0, 1000),
+            ("latency_sum".to_string(), vec![], 3.0, 1000),
+            ("latency_count".to_string(), vec![], 2.0, 1000),
+            ("up".to_string(), vec![], 1.0, 1000),
+        ]);
+        req.metadata = ["latency", "queue_size", "up"]
+            .into_iter()
+            .map(|name| MetricMetadata {
+                metric_family_name: name.to_string(),
+                ..Default::default()
+            })
+            .collect();
+        req.retain_series(|_| true);
+        let families = |req: &WriteRequest| {
+            req.metadata
+                .iter()
+                .map(|m| m.metric_family_name.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(families(&req), vec!["latency", "queue_size", "up"]);
+
+        req.retain_series(has_prefix("latency"));
+        assert_eq!(families(&req), vec!["latency", "queue_size"]);
+    }
+
+    #[test]
+    fn test_split_by_metric() {
+        let mut req = WriteRequest::from_samples(vec![
+            (
+                "a".to_string(),
+                vec![("x".to_string(), "1".to_string())],
+                1.0,
+                1,
+            ),
+            (
+                "a".to_string(),
+                vec![("x".to_string(), "2".to_string())],
+                1.0,
+                1,
+            ),
+            ("b".to_string(), vec![], 1.0, 1),
+            ("c".to_string(), vec![], 1.0, 1),
+        ]);
+        req.timeseries.push(TimeSeries {
+            labels: vec![],
+            samples: vec![Sample {
+                value: 1.0,
+                timestamp: 1,
+            }],
+            exemplars: vec![],
+        });
+
+        let groups = req.split_by_metric();
+        let mut keys = groups.keys().map(|k| k.as_str()).collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, vec!["", "a", "b", "c"]);
+
+        assert_eq!(groups["a"].timeseries.len(), 2);
+        assert_eq!(groups["b"].timeseries, vec![req.timeseries[2].clone()]);
+        assert_eq!(groups["c"].timeseries, vec![req.timeseries[3].clone()]);
+        assert_eq!(groups[""].timeseries, vec![req.timeseries[4].clone()]);
+    }
+
+    #[test]
+    fn test_from_text_format_with_metadata() {
+        let input = r#"
+# HELP requests_total Total number of requests.\nPer handler.
+# TYPE requests_total counter
+requests_total{handler="a"} 1 1
+requests_total{handler="b"} 2 1
+# TYPE temperature gauge
+temperature 21.5 1
+# TYPE unused gauge
+plain 1 1
+"#;
+        let req = WriteRequest::from_text_format_with_metadata(input.to_string()).unwrap();
+        assert_eq!(req.timeseries.len(), 4);
+
+        let mut requests = MetricMetadata {
+            metric_family_name: "requests_total".to_string(),
+            help: "Total number of requests.\nPer handler.".to_string(),
+            ..Default::default()
+        };
+        requests.set_type(MetricMetadataType::Counter);
+        let mut temperature = MetricMetadata {
+            metric_family_name: "temperature".to_string(),
+            ..Default::default()
+        };
+        temperature.set_type(MetricMetadataType::Gauge);
+        let mut unused = MetricMetadata {
+            metric_family_name: "unused".to_string(),
+            ..Default::default()
+        };
+        unused.set_type(MetricMetadataType::Gauge);
+        assert_eq!(req.metadata, vec![requests, temperature, unused]);
+
+        // Plain parsing does not emit metadata.
+        let req = WriteRequest::from_text_format(input.to_string()).unwrap();
+        assert!(req.metadata.is_empty());
+    }
+
+    #[test]
+    fn test_from_text_format_with_metadata_only() {
+        let input = "# HELP queue_size Jobs waiting.\n# TYPE queue_size gauge\n";
+        let req = WriteRequest::from_text_format_with_metadata(input.to_string()).unwrap();
+        assert!(req.timeseries.is_empty());
+
+        let mut expected = MetricMetadata {
+            metric_family_name: "queue_size".to_string(),
+            help: "Jobs waiting.".to_string(),
+            ..Default::default()
+        };
+        expected.set_type(MetricMetadataType::Gauge);
+        assert_eq!(req.metadata, vec![expected]);
+    }
+
+    #[test]
+    fn test_truncate_label_values() {
+        let mut req = WriteRequest::from_samples(vec![(
+            "x".to_string(),
+            vec![
+                ("error".to_string(), "a".repeat(100)),
+                ("city".to_string(), "Zürich".to_string()),
+            ],
+            1.0,
+            1,
+        )]);
+        let err = req.enforce_max_label_value_len(2).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "value of label 'city' is 7 bytes long, exceeding the limit of 2"
+        );
+
+        // The limit falls into the two byte 'ü'.
+        req.truncate_label_values(2);
+        let values = req.timeseries[0]
+            .labels
+            .iter()
+            .map(|l| l.value.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec!["x", "Z", "aa"]);
+        req.enforce_max_label_value_len(2).unwrap();
+
+        req.truncate_label_values(0);
+        assert!(req.timeseries[0].labels.iter().all(|l| l.value.is_empty()));
+    }
+
+    #[test]
+    fn test_metadata_byte_round_trip() {
+        let mut metadata = MetricMetadata {
+            metric_family_name: "x".to_string(),
+            help: "help".to_string(),
+            unit: "seconds".to_string(),
+            ..Default::default()
+        };
+        metadata.set_type(MetricMetadataType::Histogram);
+        let req = WriteRequest {
+            timeseries: vec![],
+            metadata: vec![metadata],
+        };
+        let bytes: Vec<u8> = req.clone().into();
+        assert_eq!(WriteRequest::try_from(bytes.as_slice()).unwrap(), req);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_encode_batches_parallel_matches_sequential() {
+        let batches = (0..32)
+            .map(|i| {
+                WriteRequest::from_samples((0..i).map(|j| {
+                    (
+                        format!("metric_{i}"),
+                        vec![("j".to_string(), j.to_string())],
+                        j as f64,
+                        j,
+                    )
+                }))
+            })
+            .collect::<Vec<_>>();
+
+        let sequential = batches
+            .iter()
+            .cloned()
+            .map(|b| b.encode_compressed().unwrap())
+            .collect::<Vec<_>>();
+        let parallel = WriteRequest::encode_batches_parallel(batches)
+            .into_iter()
+            .map(|r| r.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(parallel, sequential);
+    }
+
+    #[test]
+    fn test_encode_exemplars() {
+        let exemplar = |timestamp| Exemplar {
+            labels: vec![Label {
+                name: "trace_id".to_string(),
+                value: "abc123".to_string(),
+            }],
+            value: 0.5,
+            timestamp,
+        };
+        let req = WriteRequest {
+            timeseries: vec![TimeSeries {
+                labels: vec![Label {
+                    name: LABEL_NAME.to_string(),
+                    value: "latency".to_string(),
+                }],
+                samples: vec![Sample {
+                    value: 0.5,
+                    timestamp: 1,
+                }],
+                exemplars: vec![exemplar(2), exemplar(1)],
+            }],
+            metadata: vec![],
+        };
+
+        let bytes = req.clone().encode_proto3();
+        let decoded = WriteRequest::try_from(bytes.as_slice()).unwrap();
+        assert_eq!(decoded, req.sorted());
+        assert_eq!(
+            decoded.timeseries[0].exemplars,
+            vec![exemplar(1), exemplar(2)]
+        );
+    }
+
+    #[test]
+    fn test_sample_value_only_encodes_zero_timestamp() {
+        let req = WriteRequest::from(vec![TimeSeries {
+            labels: vec![Label {
+                name: LABEL_NAME.to_string(),
+                value: "x".to_string(),
+            }],
+            samples: vec![Sample::value_only(2.5)],
+            exemplars: vec![],
+        }]);
+        let decoded = WriteRequest::try_from(req.encode_proto3().as_slice()).unwrap();
+        assert_eq!(
+            decoded.timeseries[0].samples,
+            vec![Sample {
+                value: 2.5,
+                timestamp: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_sample_at() {
+        let time = std::time::UNIX_EPOCH + std::time::Duration::from_millis(1_700_000_000_123);
+        assert_eq!(
+            Sample::at(1.5, time).unwrap(),
+            Sample {
+                value: 1.5,
+                timestamp: 1_700_000_000_123,
+            }
+        );
+        assert_eq!(Sample::at(0.0, std::time::UNIX_EPOCH).unwrap().timestamp, 0);
+
+        let before = std::time::UNIX_EPOCH - std::time::Duration::from_secs(1);
+        let err = Sample::at(1.0, before).unwrap_err();
+        assert!(err.to_string().contains("before the Unix epoch"));
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn test_sample_at_datetime() {
+        use chrono::TimeZone;
+
+        let time = chrono::Utc.timestamp_millis_opt(1_700_000_000_123).unwrap();
+        assert_eq!(
+            Sample::at_datetime(2.0, time).unwrap(),
+            Sample {
+                value: 2.0,
+                timestamp: 1_700_000_000_123,
+            }
+        );
+
+        let before = chrono::Utc.timestamp_millis_opt(-1).unwrap();
+        assert!(Sample::at_datetime(2.0, before).is_err());
+    }
+
+    #[test]
+    fn test_downsample() {
+        let mut req = WriteRequest::from_samples(
+            (0..12).map(|i| ("x".to_string(), vec![], i as f64, i * 1000)),
+        );
+        req.downsample(5000);
+
+        let samples = req.timeseries[0]
+            .samples
+            .iter()
+            .map(|s| (s.value, s.timestamp))
+            .collect::<Vec<_>>();
+        assert_eq!(samples, vec![(4.0, 4000), (9.0, 9000), (11.0, 11000)]);
+
+        let mut series = TimeSeries {
+            labels: vec![],
+            samples: vec![
+                Sample {
+                    value: 2.0,
+                    timestamp: -1,
+                },
+                Sample {
+                    value: 1.0,
+                    timestamp: -2,
+                },
+                Sample {
+                    value: 3.0,
+                    timestamp: 0,
+                },
+            ],
+            exemplars: vec![],
+        };
+        let original = series.clone();
+        series.downsample(0);
+        assert_eq!(series, original);
+
+        series.downsample(10);
+        assert_eq!(series.samples.len(), 2);
+        assert_eq!(series.samples[0].value, 2.0);
+        assert_eq!(series.samples[1].value, 3.0);
+    }
+
+    #[test]
+    fn test_round_values() {
+        let mut req = WriteRequest::from_samples(vec![
+            ("a".to_string(), vec![], 0.123456, 1),
+            ("a".to_string(), vec![], f64::NAN, 2),
+            ("a".to_string(), vec![], f64::INFINITY, 3),
+            ("a".to_string(), vec![], -2.5, 4),
+            ("a".to_string(), vec![], f64::MAX, 5),
+        ]);
+        req.round_values(2);
+
+        let values = req.timeseries[0]
+            .samples
+            .iter()
+            .map(|s| s.value)
+            .collect::<Vec<_>>();
+        assert_eq!(values[0], 0.12);
+        assert!(values[1].is_nan());
+        assert_eq!(values[2], f64::INFINITY);
+        assert_eq!(values[3], -2.5);
+        assert_eq!(values[4], f64::MAX);
+
+        req.round_values(0);
+        assert_eq!(req.timeseries[0].samples[0].value, 0.0);
+        assert_eq!(req.timeseries[0].samples[3].value, -3.0);
+    }
+
+    #[test]
+    fn test_check_sorted() {
+        let req = WriteRequest::from_samples(vec![
+            (
+                "a".to_string(),
+                vec![("z".to_string(), "1".to_string())],
+                1.0,
+                2,
+            ),
+            (
+                "a".to_string(),
+                vec![("z".to_string(), "1".to_string())],
+                1.0,
+                1,
+            ),
+        ]);
+        req.check_sorted().unwrap();
+
+        let mut unsorted_labels = req.clone();
+        unsorted_labels.timeseries[0].labels.reverse();
+        let err = unsorted_labels.check_sorted().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "series 0 ('a'): label '__name__' must be sorted before label 'z'"
+        );
+
+        let mut unsorted_samples = req.clone();
+        unsorted_samples.timeseries[0].samples.reverse();
+        let err = unsorted_samples.check_sorted().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "series 0 ('a'): sample with timestamp 1 must be sorted before timestamp 2"
+        );
+
+        // check_sorted does not modify the request.
+        assert_eq!(unsorted_samples.timeseries[0].samples[0].timestamp, 2);
+        unsorted_samples.sort();
+        unsorted_samples.check_sorted().unwrap();
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_build_http_request_from_bytes_reuses_body() {
+        let req = WriteRequest::from_samples(vec![("a".to_string(), vec![], 1.0, 1)]);
+        let body = req.clone().encode_compressed().unwrap();
+
+        let first = url::Url::parse("http://first/api/v1/write").unwrap();
+        let second = url::Url::parse("http://second/api/v1/write").unwrap();
+        let a = WriteRequest::build_http_request_from_bytes(
+            body.clone(),
+            &first,
+            "test",
+            BodyEncoding::Snappy,
+        )
+        .unwrap();
+        let b = WriteRequest::build_http_request_from_bytes(
+            body.clone(),
+            &second,
+            "test",
+            BodyEncoding::Snappy,
+        )
+        .unwrap();
+
+        assert_eq!(a.uri(), "http://first/api/v1/write");
+        assert_eq!(b.uri(), "http://second/api/v1/write");
+        assert_eq!(a.body(), &body);
+        assert_eq!(b.body(), &body);
+        assert_eq!(a.headers()[http::header::CONTENT_ENCODING], "snappy");
+
+        // Equivalent to building the request directly.
+        let direct = req.build_http_request(&first, "test").unwrap();
+        assert_eq!(direct.body(), a.body());
+        assert_eq!(direct.headers(), a.headers());
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_build_http_request_with() {
+        let req = WriteRequest::from_samples(vec![("a".to_string(), vec![], 1.0, 1)]);
+        let endpoint = url::Url::parse("http://localhost/api/v1/write").unwrap();
+
+        let http_req = req
+            .clone()
+            .build_http_request_with(&endpoint, "test", |builder| {
+                builder
+                    .method(http::Method::PUT)
+                    .header("x-custom", "yes")
+                    .header(http::header::CONTENT_TYPE, "text/plain")
+            })
+            .unwrap();
+        assert_eq!(http_req.method(), http::Method::PUT);
+        assert_eq!(http_req.headers()["x-custom"], "yes");
+        assert_eq!(
+            http_req
+                .headers()
+                .get_all(http::header::CONTENT_TYPE)
+                .iter()
+                .collect::<Vec<_>>(),
+            vec![CONTENT_TYPE]
+        );
+        assert_eq!(http_req.headers()[http::header::USER_AGENT], "test");
+
+        let plain = req.clone().build_http_request(&endpoint, "test").unwrap();
+        assert_eq!(http_req.body(), plain.body());
+        assert_eq!(
+            http_req.headers()[http::header::CONTENT_ENCODING],
+            plain.headers()[http::header::CONTENT_ENCODING]
+        );
+    }
+
+    #[test]
+    fn test_enforce_max_series() {
+        let req =
+            WriteRequest::from_samples((0..3).map(|i| (format!("metric_{i}"), vec![], 1.0, 1)));
+        req.enforce_max_series(4).unwrap();
+        req.enforce_max_series(3).unwrap();
+        let err = req.enforce_max_series(2).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "request contains 3 series, exceeding the limit of 2"
+        );
+    }
+
+    #[test]
+    fn test_enforce_max_labels_per_series() {
+        let req = WriteRequest::from_samples(vec![(
+            "a".to_string(),
+            vec![
+                ("x".to_string(), "1".to_string()),
+                ("y".to_string(), "2".to_string()),
+            ],
+            1.0,
+            1,
+        )]);
+        req.enforce_max_labels_per_series(4).unwrap();
+        req.enforce_max_labels_per_series(3).unwrap();
+        let err = req.enforce_max_labels_per_series(2).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "series 'a' has 3 labels, exceeding the limit of 2"
+        );
+    }
+
+    #[test]
+    fn test_encode_framed_reader() {
+        use std::io::Read;
+
+        let req =
+            WriteRequest::from_samples((0..100).map(|i| ("a".to_string(), vec![], i as f64, i)));
+        let proto = req.clone().encode_proto3();
+        let raw = req.clone().encode_compressed().unwrap();
+
+        let mut framed = Vec::new();
+        req.encode_framed_reader().read_to_end(&mut framed).unwrap();
+
+        // The framing format is not compatible with the raw block format.
+        assert_ne!(framed, raw);
+        assert!(snap::raw::Decoder::new().decompress_vec(&framed).is_err());
+
+        let mut decoded = Vec::new();
+        snap::read::FrameDecoder::new(framed.as_slice())
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, proto);
+        assert_eq!(
+            snap::raw::Decoder::new().decompress_vec(&raw).unwrap(),
+            proto
+        );
+    }
+
+    #[cfg(feature = "influx")]
+    #[test]
+    fn test_to_influx_line_protocol() {
+        let req = WriteRequest::from_samples(vec![
+            (
+                "http requests".to_string(),
+                vec![
+                    ("path".to_string(), "/a,b=c".to_string()),
+                    ("method".to_string(), "GET".to_string()),
+                ],
+                1.5,
+                1000,
+            ),
+            (
+                "http requests".to_string(),
+                vec![
+                    ("path".to_string(), "/a,b=c".to_string()),
+                    ("method".to_string(), "GET".to_string()),
+                ],
+                f64::NAN,
+                2000,
+            ),
+        ]);
+        assert_eq!(
+            req.to_influx_line_protocol(),
+            "http\\ requests,method=GET,path=/a\\,b\\=c value=1.5 1000000000\n"
+        );
+    }
+
+    #[test]
+    fn test_into_iter() {
+        let req = WriteRequest::from_samples(vec![
+            ("a".to_string(), vec![], 1.0, 1),
+            ("b".to_string(), vec![], 2.0, 1),
+        ]);
+
+        let borrowed = (&req).into_iter().cloned().collect::<Vec<_>>();
+        assert_eq!(borrowed, req.timeseries);
+
+        let expected = req.timeseries.clone();
+        let mut names = Vec::new();
+        for series in &req {
+            names.push(series.labels[0].value.clone());
+        }
+        assert_eq!(names, vec!["a", "b"]);
+
+        let owned = req.into_iter().collect::<Vec<_>>();
+        assert_eq!(owned, expected);
+    }
+
+    #[test]
+    fn test_from_text_format_special_value_spellings() {
+        let cases = [
+            ("NaN", f64::NAN),
+            ("nan", f64::NAN),
+            ("Nan", f64::NAN),
+            ("+Inf", f64::INFINITY),
+            ("Inf", f64::INFINITY),
+            ("inf", f64::INFINITY),
+            ("Infinity", f64::INFINITY),
+            ("+Infinity", f64::INFINITY),
+            ("-Inf", f64::NEG_INFINITY),
+            ("-infinity", f64::NEG_INFINITY),
+        ];
+        for (spelling, expected) in cases {
+            let req = WriteRequest::from_text_format(format!("x {spelling} 1")).unwrap();
+            assert_eq!(req.timeseries.len(), 1, "{spelling}");
+            let value = req.timeseries[0].samples[0].value;
+            if expected.is_nan() {
+                assert!(value.is_nan(), "{spelling}");
+            } else {
+                assert_eq!(value, expected, "{spelling}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let req = WriteRequest::from_samples(vec![
+            (
+                "job:a".to_string(),
+                vec![("instance".to_string(), "a".to_string())],
+                1.0,
+                1,
+            ),
+            ("job:b".to_string(), vec![], 1.0, 1),
+        ]);
+        req.validate().unwrap();
+
+        let mut invalid = req.clone();
+        invalid.timeseries[0].labels.push(Label {
+            name: "z-invalid".to_string(),
+            value: "x".to_string(),
+        });
+        invalid.timeseries[1].labels[0].value = "1b".to_string();
+        invalid.timeseries[1].samples.clear();
+        invalid.timeseries.push(TimeSeries {
+            labels: vec![],
+            samples: vec![Sample {
+                value: 1.0,
+                timestamp: 1,
+            }],
+            exemplars: vec![],
+        });
+
+        let err = invalid.validate().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            [
+                "series 0 ('job:a'): invalid label name 'z-invalid'",
+                "series 1: invalid metric name: '1b'",
+                "series 1 ('1b'): no samples",
+                "series 2: missing metric name label '__name__'",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn test_append_series() {
+        let name = MetricName::new("x").unwrap();
+        let sample = Sample {
+            value: 1.0,
+            timestamp: 1,
+        };
+        let mut req = WriteRequest::default();
+
+        req.append_series(TimeSeries::new(name.clone(), vec![], vec![sample.clone()]))
+            .unwrap();
+        assert_eq!(req.timeseries.len(), 1);
+
+        let err = req
+            .append_series(TimeSeries::new(name, vec![], vec![]))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "series 'x' has no samples");
+
+        let err = req
+            .append_series(TimeSeries {
+                labels: vec![],
+                samples: vec![sample],
+                exemplars: vec![],
+            })
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "series is missing metric name label '__name__'"
+        );
+        assert_eq!(req.timeseries.len(), 1);
+    }
+
+    #[test]
+    fn test_group_by() {
+        let pod = |node: &str, pod: &str| {
+            vec![
+                ("node".to_string(), node.to_string()),
+                ("pod".to_string(), pod.to_string()),
+            ]
+        };
+        let mut req = WriteRequest::from_samples(vec![
+            ("cpu".to_string(), pod("n1", "a"), 1.0, 1000),
+            ("cpu".to_string(), pod("n1", "a"), 2.0, 2000),
+            ("cpu".to_string(), pod("n1", "b"), 3.0, 2000),
+            ("cpu".to_string(), pod("n1", "b"), 4.0, 3000),
+            ("cpu".to_string(), pod("n2", "c"), 5.0, 1000),
+        ]);
+        req.group_by(&["node"]);
+
+        let expected = WriteRequest::from_samples(vec![
+            (
+                "cpu".to_string(),
+                vec![("node".to_string(), "n1".to_string())],
+                1.0,
+                1000,
+            ),
+            // The sample of pod b comes later in the request, so it wins.
+            (
+                "cpu".to_string(),
+                vec![("node".to_string(), "n1".to_string())],
+                3.0,
+                2000,
+            ),
+            (
+                "cpu".to_string(),
+                vec![("node".to_string(), "n1".to_string())],
+                4.0,
+                3000,
+            ),
+            (
+                "cpu".to_string(),
+                vec![("node".to_string(), "n2".to_string())],
+                5.0,
+                1000,
+            ),
+        ]);
+        assert_eq!(req, expected);
+
+        req.group_by(&[]);
+        assert_eq!(req.timeseries.len(), 1);
+        assert_eq!(req.timeseries[0].labels.len(), 1);
+    }
+
+    #[test]
+    fn test_rename_metric() {
+        let mut req = WriteRequest::from_text_format_with_metadata(
+            "# TYPE old_jobs gauge\nold_jobs{queue=\"a\"} 1 1000\nother 2 1000\n".to_string(),
+        )
+        .unwrap();
+        req.rename_metric("old_jobs", "jobs");
+
+        let expected = WriteRequest::from_text_format_with_metadata(
+            "# TYPE jobs gauge\njobs{queue=\"a\"} 1 1000\nother 2 1000\n".to_string(),
+        )
+        .unwrap();
+        assert_eq!(req, expected);
+
+        req.rename_metric("missing", "x");
+        assert_eq!(req, expected);
+    }
+
+    #[test]
+    fn test_rename_metric_collision() {
+        let mut req = WriteRequest::from_text_format_preserve_order(
+            "old_jobs 1 1000\njobs 2 2000\nup 1 1000\n".to_string(),
+        )
+        .unwrap();
+        req.rename_metric("old_jobs", "jobs");
+
+        // `old_jobs` and `jobs` now are the same series.
+        assert_eq!(req.timeseries.len(), 3);
+        req.normalize();
+
+        let expected = WriteRequest::from_text_format_preserve_order(
+            "jobs 1 1000\njobs 2 2000\nup 1 1000\n".to_string(),
+        )
+        .unwrap();
+        assert_eq!(req, expected);
+        assert_eq!(req.timeseries[0].samples.len(), 2);
+    }
+
+    #[test]
+    fn test_prefix_metric_names() {
+        let mut req = WriteRequest::from_text_format_with_metadata(
+            "# TYPE jobs gauge\njobs 1 1000\napp_jobs 2 2000\n".to_string(),
+        )
+        .unwrap();
+        req.prefix_metric_names("app_");
+
+        let expected = WriteRequest::from_text_format_with_metadata(
+            "# TYPE app_jobs gauge\napp_jobs 1 1000\napp_app_jobs 2 2000\n".to_string(),
+        )
+        .unwrap();
+        assert_eq!(req, expected);
+    }
+
+    #[test]
+    fn test_normalize_merges_duplicate_series() {
+        let name = MetricName::new("x").unwrap();
+        let label = |name: &str, value: &str| Label {
+            name: name.to_string(),
+            value: value.to_string(),
+        };
+        let sample = |value, timestamp| Sample { value, timestamp };
+        let exemplar = |value, timestamp| Exemplar {
+            labels: vec![label("trace_id", "t")],
+            value,
+            timestamp,
+        };
+        let mut first = TimeSeries::new(
+            name.clone(),
+            vec![label("a", "1"), label("b", "2")],
+            vec![sample(1.0, 1), sample(3.0, 3)],
+        );
+        first.exemplars = vec![exemplar(1.0, 1), exemplar(3.0, 3)];
+        let mut req = WriteRequest {
+            timeseries: vec![
+                first,
+                TimeSeries::new(name.clone(), vec![label("a", "2")], vec![sample(1.0, 1)]),
+                TimeSeries {
+                    labels: vec![label("b", "2"), label("a", "1"), label(LABEL_NAME, "x")],
+                    samples: vec![sample(4.0, 4), sample(2.0, 2), sample(30.0, 3)],
+                    exemplars: vec![exemplar(30.0, 3), exemplar(2.0, 2)],
+                },
+            ],
+            metadata: vec![],
+        };
+        req.normalize();
+
+        let mut merged = TimeSeries::new(
+            name.clone(),
+            vec![label("a", "1"), label("b", "2")],
+            vec![
+                sample(1.0, 1),
+                sample(2.0, 2),
+                sample(30.0, 3),
+                sample(4.0, 4),
+            ],
+        );
+        merged.exemplars = vec![exemplar(1.0, 1), exemplar(2.0, 2), exemplar(30.0, 3)];
+        assert_eq!(
+            req.timeseries,
+            vec![
+                merged,
+                TimeSeries::new(name, vec![label("a", "2")], vec![sample(1.0, 1)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_duplicate_labels() {
+        let mut req = WriteRequest::from_samples(vec![(
+            "a".to_string(),
+            vec![("x".to_string(), "1".to_string())],
+            1.0,
+            1,
+        )]);
+        req.timeseries[0].labels.push(Label {
+            name: "x".to_string(),
+            value: "2".to_string(),
+        });
+        let err = req.validate().unwrap_err();
+        assert_eq!(err.to_string(), "series 0 ('a'): duplicate label 'x'");
+    }
+
+    #[test]
+    fn test_from_text_format_verbose() {
+        let input = r#"# TYPE latency histogram
+latency_bucket{le="0.1"} 1 1
+latency_bucket{le="+Inf"} 2 1
+# a plain comment
+up 1 1
+up{job="x"} not-a-number 1
+down 1 tomorrow
+=== garbage ===
+"#;
+        let (req, warnings) = WriteRequest::from_text_format_verbose(input.to_string()).unwrap();
+
+        let names = req
+            .timeseries
+            .iter()
+            .map(|s| s.labels[0].value.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["down", "up"]);
+
+        let warnings = warnings.iter().map(|w| w.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            warnings,
+            vec![
+                "line 6: invalid sample value 'not-a-number', line skipped",
+                "line 7: invalid timestamp 'tomorrow', using the current time",
+                "line 8: could not parse line, skipped: '=== garbage ==='",
+                "histogram 'latency' not supported yet, dropped",
+            ]
+        );
+
+        // The non-verbose version still fails on unsupported types.
+        let err = WriteRequest::from_text_format(input.to_string()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 2: histogram 'latency' not supported yet"
+        );
+    }
+
+    #[test]
+    fn test_from_text_format_error_line_number() {
+        let mut input = (0..10).map(|i| format!("m{i} {i} 1\n")).collect::<String>();
+        input.push_str("m10{a=\"b\" 10 1\n");
+        input.push_str("m11 11 1\n");
+        // Lines the parser can not handle are skipped, not errors.
+        let req = WriteRequest::from_text_format(input).unwrap();
+        assert_eq!(req.timeseries.len(), 11);
+        assert!(req.timeseries.iter().all(|s| s.labels[0].value != "m10"));
+
+        let input = "up 1 1\n# TYPE rpc summary\nrpc{quantile=\"0.5\"} 1 1\nrpc_sum 1 1\n";
+        let err = WriteRequest::from_text_format(input.to_string()).unwrap_err();
+        assert_eq!(err.to_string(), "line 3: summary 'rpc' not supported yet");
+    }
+
+    #[test]
+    fn test_iter_samples() {
+        let req = WriteRequest::from_samples(vec![
+            ("a".to_string(), vec![], 1.0, 1),
+            ("a".to_string(), vec![], 2.0, 2),
+            ("b".to_string(), vec![], 3.0, 1),
+            (
+                "b".to_string(),
+                vec![("x".to_string(), "1".to_string())],
+                4.0,
+                1,
+            ),
+        ]);
+        assert_eq!(req.timeseries.len(), 3);
+        assert_eq!(req.iter_samples().count(), 4);
+
+        let total: f64 = req.iter_samples().map(|(_, sample)| sample.value).sum();
+        assert_eq!(total, 10.0);
+
+        let (labels, sample) = req.iter_samples().last().unwrap();
+        assert_eq!(sample.value, 4.0);
+        assert!(labels.iter().any(|l| l.name == "x" && l.value == "1"));
+    }
+
+    #[test]
+    fn test_timestamp_range() {
+        assert_eq!(WriteRequest::default().timestamp_range(), None);
+
+        let mut req = WriteRequest::from_samples(vec![
+            ("a".to_string(), vec![], 1.0, 2000),
+            ("a".to_string(), vec![], 1.0, 3000),
+            ("b".to_string(), vec![], 1.0, 500),
+            ("c".to_string(), vec![], 1.0, 7000),
+        ]);
+        assert_eq!(req.timestamp_range(), Some((500, 7000)));
+
+        req.clear_samples();
+        assert_eq!(req.timestamp_range(), None);
+    }
+
+    #[test]
+    fn test_detect_suspicious_timestamps() {
+        let now = 1_700_000_000_000;
+        let req = WriteRequest::from_samples(vec![
+            ("a_millis".to_string(), vec![], 1.0, now - 60_000),
+            ("b_seconds".to_string(), vec![], 1.0, now / 1000),
+            ("c_mixed".to_string(), vec![], 1.0, now),
+            ("c_mixed".to_string(), vec![], 1.0, now / 1000 + 1),
+            ("d_nanos".to_string(), vec![], 1.0, now * 1_000_000),
+            ("e_old_millis".to_string(), vec![], 1.0, 946_684_800_000),
+        ]);
+        assert_eq!(req.detect_suspicious_timestamps(now), vec![1, 2, 3]);
+        assert_eq!(req.detect_suspicious_timestamps(0), Vec::<usize>::new());
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_encode_into_reuses_buffers() {
+        let sorted = WriteRequest::from_samples(vec![
+            (
+                "a".to_string(),
+                vec![("x".to_string(), "1".to_string())],
+                1.0,
+                1,
+            ),
+            ("b".to_string(), vec![], 2.0, 2),
+        ]);
+        let mut unsorted = sorted.clone();
+        unsorted.timeseries[0].labels.reverse();
+
+        let mut proto = Vec::new();
+        let mut scratch = Vec::new();
+        let mut compressed = Vec::new();
+        for req in [&sorted, &unsorted, &sorted] {
+            req.encode_into(&mut proto);
+            assert_eq!(proto, req.clone().encode_proto3());
+            req.encode_compressed_into(&mut scratch, &mut compressed)
+                .unwrap();
+            assert_eq!(compressed, req.clone().encode_compressed().unwrap());
+        }
+
+        // Encoding a smaller request keeps the allocation.
+        let capacity = proto.capacity();
+        WriteRequest::from_samples(vec![("c".to_string(), vec![], 3.0, 3)]).encode_into(&mut proto);
+        assert_eq!(proto.capacity(), capacity);
+    }
+
+    #[test]
+    fn test_stats() {
+        let req = WriteRequest::from_samples(vec![
+            (
+                "a".to_string(),
+                vec![("x".to_string(), "1".to_string())],
+                1.0,
+                3000,
+            ),
+            (
+                "a".to_string(),
+                vec![("x".to_string(), "2".to_string())],
+                1.0,
+                1000,
+            ),
+            (
+                "a".to_string(),
+                vec![("x".to_string(), "2".to_string())],
+                1.0,
+                2000,
+            ),
+            ("b".to_string(), vec![], 1.0, 5000),
+        ]);
+        assert_eq!(
+            req.stats(),
+            WriteRequestStats {
+                series: 3,
+                samples: 4,
+                metrics: 2,
+                earliest: Some(1000),
+                latest: Some(5000),
+            }
+        );
+        assert_eq!(
+            WriteRequest::from_samples(vec![]).stats(),
+            WriteRequestStats::default()
+        );
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_endpoint_path_warning() {
+        for expected in [
+            "http://localhost:9090/api/v1/write",
+            "https://mimir.example/api/v1/push",
+            "https://cortex.example/api/prom/push/",
+            "http://thanos:19291/api/v1/receive",
+        ] {
+            let url = url::Url::parse(expected).unwrap();
+            assert_eq!(endpoint_path_warning(&url), None, "{expected}");
+        }
+
+        for suspicious in [
+            "http://localhost:9090",
+            "http://localhost:9090/",
+            "http://localhost:9090/api/v1/query",
+            "http://localhost:9090/api/v1/query_range",
+            "http://localhost:9090/api/v1/read",
+            "http://localhost:9090/metrics/",
+        ] {
+            let url = url::Url::parse(suspicious).unwrap();
+            assert!(endpoint_path_warning(&url).is_some(), "{suspicious}");
+        }
+
+        let url = url::Url::parse("http://localhost:9090/api/v1/query").unwrap();
+        assert_eq!(
+            endpoint_path_warning(&url).unwrap(),
+            "endpoint path '/api/v1/query' does not look like a remote write endpoint, \
+remote write endpoints usually have a path like /api/v1/write"
+        );
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_status_class() {
+        assert!(!is_success_status(199));
+        assert!(is_success_status(200));
+        assert!(is_success_status(204));
+        assert!(is_success_status(299));
+        assert!(!is_success_status(300));
+
+        let cases = [
+            (100, StatusClass::Permanent),
+            (200, StatusClass::Success),
+            (299, StatusClass::Success),
+            (308, StatusClass::Permanent),
+            (400, StatusClass::Permanent),
+            (428, StatusClass::Permanent),
+            (429, StatusClass::Retryable),
+            (430, StatusClass::Permanent),
+            (499, StatusClass::Permanent),
+            (500, StatusClass::Retryable),
+            (503, StatusClass::Retryable),
+            (599, StatusClass::Retryable),
+            (600, StatusClass::Permanent),
+        ];
+        for (code, class) in cases {
+            assert_eq!(StatusClass::of(code), class, "{code}");
+        }
+    }
+
+    #[test]
+    fn test_extend() {
+        let mut req = WriteRequest::from_samples(vec![("a".to_string(), vec![], 1.0, 2000)]);
+        let more = WriteRequest::from_samples(vec![
+            ("a".to_string(), vec![], 2.0, 1000),
+            ("b".to_string(), vec![], 3.0, 1000),
+        ]);
+        req.extend(more.clone());
+        // Appended as they are, without merging.
+        assert_eq!(req.timeseries.len(), 3);
+        assert_eq!(req.timeseries[1..], more.timeseries[..]);
+
+        req.normalize();
+        let expected = WriteRequest::from_samples(vec![
+            ("a".to_string(), vec![], 1.0, 2000),
+            ("a".to_string(), vec![], 2.0, 1000),
+            ("b".to_string(), vec![], 3.0, 1000),
+        ]);
+        assert_eq!(req, expected);
+    }
+
+    #[test]
+    fn test_series_accessors() {
+        let mut req = WriteRequest::from_samples(vec![
+            ("a".to_string(), vec![], 1.0, 1000),
+            ("b".to_string(), vec![], 2.0, 1000),
+        ]);
+        assert_eq!(req.series(), &req.timeseries[..]);
+        assert_eq!(AsRef::<[TimeSeries]>::as_ref(&req).len(), 2);
+
+        for series in req.series_mut() {
+            series.samples[0].value *= 10.0;
+        }
+        let values = req
+            .series()
+            .iter()
+            .map(|s| s.samples[0].value)
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![10.0, 20.0]);
+    }
+
+    #[test]
+    fn test_check_integer_precision() {
+        let limit = (1u64 << 53) as f64;
+        let req = WriteRequest::from_samples(vec![
+            ("a".to_string(), vec![], limit, 1),
+            ("b".to_string(), vec![], limit * 2.0, 1),
+            ("c".to_string(), vec![], 1.0, 1),
+            ("c".to_string(), vec![], -limit * 4.0, 2),
+            ("d".to_string(), vec![], f64::INFINITY, 1),
+            ("e".to_string(), vec![], f64::NAN, 1),
+        ]);
+        assert_eq!(req.check_integer_precision(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_from_metrics() {
+        let req = WriteRequest::from_metrics(vec![
+            (
+                "temp".to_string(),
+                vec![],
+                Metric::Gauge { value: 3.0 },
+                1000,
+            ),
+            (
+                "temp".to_string(),
+                vec![],
+                Metric::Gauge { value: 1.0 },
+                2000,
+            ),
+            (
+                "jobs".to_string(),
+                vec![],
+                Metric::Untyped { value: 1.0 },
+                1000,
+            ),
+        ])
+        .unwrap();
+        assert_eq!(
+            req.timeseries,
+            WriteRequest::from_samples(vec![
+                ("temp".to_string(), vec![], 3.0, 1000),
+                ("temp".to_string(), vec![], 1.0, 2000),
+                ("jobs".to_string(), vec![], 1.0, 1000),
+            ])
+            .timeseries
+        );
+        let types = req
+            .metadata
+            .iter()
+            .map(|m| (m.metric_family_name.as_str(), m.r#type()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            types,
+            vec![
+                ("jobs", MetricMetadataType::Unknown),
+                ("temp", MetricMetadataType::Gauge),
+            ]
+        );
+
+        let err = WriteRequest::from_metrics(vec![
+            ("x".to_string(), vec![], Metric::Counter { value: 1.0 }, 1),
+            ("x".to_string(), vec![], Metric::Gauge { value: 1.0 }, 2),
+        ])
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "metric 'x' is used as both counter and gauge"
+        );
+    }
+
+    #[test]
+    fn test_check_counter_monotonicity() {
+        let pod = |name: &str| vec![("pod".to_string(), name.to_string())];
+        let req = WriteRequest::from_metrics(vec![
+            // Increasing, given out of order.
+            (
+                "jobs".to_string(),
+                pod("a"),
+                Metric::Counter { value: 5.0 },
+                2000,
+            ),
+            (
+                "jobs".to_string(),
+                pod("a"),
+                Metric::Counter { value: 1.0 },
+                1000,
+            ),
+            // Reset.
+            (
+                "jobs".to_string(),
+                pod("b"),
+                Metric::Counter { value: 5.0 },
+                1000,
+            ),
+            (
+                "jobs".to_string(),
+                pod("b"),
+                Metric::Counter { value: 2.0 },
+                2000,
+            ),
+            // Gauges may decrease.
+            (
+                "temp".to_string(),
+                vec![],
+                Metric::Gauge { value: 5.0 },
+                1000,
+            ),
+            (
+                "temp".to_string(),
+                vec![],
+                Metric::Gauge { value: 2.0 },
+                2000,
+            ),
+        ])
+        .unwrap();
+        assert_eq!(req.check_counter_monotonicity(), vec![1]);
+
+        // Without metadata, the type is unknown.
+        let req = WriteRequest::from_text_format("jobs 5 1000\njobs 2 2000\n".to_string()).unwrap();
+        assert!(req.check_counter_monotonicity().is_empty());
+        let req = WriteRequest::from_text_format_with_metadata(
+            "# TYPE jobs counter\njobs 5 1000\njobs 2 2000\n".to_string(),
+        )
+        .unwrap();
+        assert_eq!(req.check_counter_monotonicity(), vec![0]);
+    }
+
+    #[test]
+    fn test_text_format_round_trip() {
+        let input = r#"
+# TYPE mycounter counter
+mycounter 100 100
+http_requests_total{method="post",code="200"} 1027 1395066363000
+http_requests_total{method="post",code="200"} 50 1000
+temperature{room="kitchen"} -1.5 2000
+"#;
+
+        let req = WriteRequest::from_text_format(input.to_string()).unwrap();
+        let text = req.to_text_format();
+        assert_eq!(
+            text,
+            r#"http_requests_total{code="200",method="post"} 50 1000
+http_requests_total{code="200",method="post"} 1027 1395066363000
+mycounter 100 100
+temperature{room="kitchen"} -1.5 2000
+"#
+        );
+
+        let reparsed = WriteRequest::from_text_format(text).unwrap();
+        assert_eq!(reparsed, req);
+    }
+
+    #[test]
+    fn test_format_sample_value_round_trip() {
+        let values = [
+            1e20,
+            1e21,
+            0.0000001,
+            0.0001,
+            5e-324,
+            f64::MIN_POSITIVE / 3.0,
+            f64::MAX,
+            -f64::MAX,
+            0.1 + 0.2,
+            123456789.123,
+            -0.0,
+            0.0,
+        ];
+        for value in values {
+            let text = format_sample_value(value);
+            assert!(text.len() < 30, "{text}");
+            let req = WriteRequest::from_text_format(format!("x {text} 1\n")).unwrap();
+            let parsed = req.timeseries[0].samples[0].value;
+            assert_eq!(parsed.to_bits(), value.to_bits(), "{text}");
+        }
+
+        assert_eq!(format_sample_value(1e20), "100000000000000000000");
+        assert_eq!(format_sample_value(1e21), "1e21");
+        assert_eq!(format_sample_value(0.0000001), "1e-7");
+        assert_eq!(format_sample_value(0.0001), "0.0001");
+        assert_eq!(format_sample_value(5e-324), "5e-324");
+        assert_eq!(format_sample_value(-2.5e-10), "-2.5e-10");
+    }
+
+    #[test]
+    fn test_text_format_round_trip_escaped_label_values() {
+        let value = "say \"hi\"\nbye";
+        let req = WriteRequest {
+            timeseries: vec![TimeSeries {
+                labels: vec![
+                    Label {
+                        name: LABEL_NAME.to_string(),
+                        value: "x".to_string(),
+                    },
+                    Label {
+                        name: "msg".to_string(),
+                        value: value.to_string(),
                     },
-                    TimeSeries {
-                        labels: vec![Label {
-                            name: LABEL_NAME.to_string(),
-                            value: "mycounter".to_string()
-                        },],
-                        samples: vec![Sample {
-                            value: 100.0,
-                            timestamp: 100,
+                ],
+                samples: vec![Sample {
+                    value: 1.0,
+                    timestamp: 1,
+                }],
+                exemplars: vec![],
+            }],
+            metadata: vec![],
+        };
+
+        let text = req.to_text_format();
+        assert_eq!(text, concat!(r#"x{msg="say \"hi\"\nbye"} 1 1"#, "\n"));
+
+        let reparsed = WriteRequest::from_text_format(text).unwrap();
+        assert_eq!(reparsed, req);
+    }
+
+    #[test]
+    fn test_text_format_escapes_label_values() {
+        let req = WriteRequest {
+            timeseries: vec![TimeSeries {
+                labels: vec![
+                    Label {
+                        name: LABEL_NAME.to_string(),
+                        value: "x".to_string(),
+                    },
+                    Label {
+                        name: "path".to_string(),
+                        value: r#"C:\dir "a""#.to_string(),
+                    },
+                ],
+                samples: vec![Sample {
+                    value: f64::INFINITY,
+                    timestamp: 1,
+                }],
+                exemplars: vec![],
+            }],
+            metadata: vec![],
+        };
+
+        assert_eq!(
+            req.to_string(),
+            concat!(r#"x{path="C:\\dir \"a\""} +Inf 1"#, "\n")
+        );
+    }
+
+    fn descriptor_field(
+        name: &str,
+        number: u32,
+        ty: prost_types::field_descriptor_proto::Type,
+        type_name: Option<&str>,
+        repeated: bool,
+    ) -> prost_types::FieldDescriptorProto {
+        use prost_types::field_descriptor_proto::Label;
+
+        prost_types::FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number as i32),
+            label: Some(if repeated {
+                Label::Repeated
+            } else {
+                Label::Optional
+            } as i32),
+            r#type: Some(ty as i32),
+            type_name: type_name.map(|t| format!(".prometheus.{t}")),
+            ..Default::default()
+        }
+    }
+
+    /// The descriptor stored in `remote_write.desc`.
+    ///
+    /// Regenerate the file with `UPDATE_PROTO_DESCRIPTOR=1 cargo test` after
+    /// changing the messages.
+    fn remote_write_descriptor_set() -> prost_types::FileDescriptorSet {
+        use prost_types::{
+            descriptor_proto::ReservedRange, field_descriptor_proto::Type, DescriptorProto,
+            EnumDescriptorProto, EnumValueDescriptorProto, FileDescriptorProto, FileDescriptorSet,
+        };
+
+        use crate::field_tags::*;
+
+        let message = |name: &str, field: Vec<prost_types::FieldDescriptorProto>| DescriptorProto {
+            name: Some(name.to_string()),
+            field,
+            ..Default::default()
+        };
+        let metric_type = EnumDescriptorProto {
+            name: Some("MetricType".to_string()),
+            value: [
+                "UNKNOWN",
+                "COUNTER",
+                "GAUGE",
+                "HISTOGRAM",
+                "GAUGEHISTOGRAM",
+                "SUMMARY",
+                "INFO",
+                "STATESET",
+            ]
+            .iter()
+            .enumerate()
+            .map(|(number, name)| EnumValueDescriptorProto {
+                name: Some(name.to_string()),
+                number: Some(number as i32),
+                options: None,
+            })
+            .collect(),
+            ..Default::default()
+        };
+
+        FileDescriptorSet {
+            file: vec![FileDescriptorProto {
+                name: Some("remote_write.proto".to_string()),
+                package: Some("prometheus".to_string()),
+                syntax: Some("proto3".to_string()),
+                message_type: vec![
+                    DescriptorProto {
+                        reserved_range: vec![ReservedRange {
+                            start: Some(2),
+                            end: Some(3),
                         }],
+                        ..message(
+                            "WriteRequest",
+                            vec![
+                                descriptor_field(
+                                    "timeseries",
+                                    WRITE_REQUEST_TIMESERIES,
+                                    Type::Message,
+                                    Some("TimeSeries"),
+                                    true,
+                                ),
+                                descriptor_field(
+                                    "metadata",
+                                    WRITE_REQUEST_METADATA,
+                                    Type::Message,
+                                    Some("MetricMetadata"),
+                                    true,
+                                ),
+                            ],
+                        )
                     },
-                    TimeSeries {
-                        labels: vec![Label {
+                    message(
+                        "TimeSeries",
+                        vec![
+                            descriptor_field(
+                                "labels",
+                                TIME_SERIES_LABELS,
+                                Type::Message,
+                                Some("Label"),
+                                true,
+                            ),
+                            descriptor_field(
+                                "samples",
+                                TIME_SERIES_SAMPLES,
+                                Type::Message,
+                                Some("Sample"),
+                                true,
+                            ),
+                            descriptor_field(
+                                "exemplars",
+                                TIME_SERIES_EXEMPLARS,
+                                Type::Message,
+                                Some("Exemplar"),
+                                true,
+                            ),
+                        ],
+                    ),
+                    message(
+                        "Label",
+                        vec![
+                            descriptor_field("name", LABEL_NAME, Type::String, None, false),
+                            descriptor_field("value", LABEL_VALUE, Type::String, None, false),
+                        ],
+                    ),
+                    message(
+                        "Sample",
+                        vec![
+                            descriptor_field("value", SAMPLE_VALUE, Type::Double, None, false),
+                            descriptor_field(
+                                "timestamp",
+                                SAMPLE_TIMESTAMP,
+                                Type::Int64,
+                                None,
+                                false,
+                            ),
+                        ],
+                    ),
+                    message(
+                        "Exemplar",
+                        vec![
+                            descriptor_field(
+                                "labels",
+                                EXEMPLAR_LABELS,
+                                Type::Message,
+                                Some("Label"),
+                                true,
+                            ),
+                            descriptor_field("value", EXEMPLAR_VALUE, Type::Double, None, false),
+                            descriptor_field(
+                                "timestamp",
+                                EXEMPLAR_TIMESTAMP,
+                                Type::Int64,
+                                None,
+                                false,
+                            ),
+                        ],
+                    ),
+                    DescriptorProto {
+                        enum_type: vec![metric_type],
+                        ..message(
+                            "MetricMetadata",
+                            vec![
+                                descriptor_field(
+                                    "type",
+                                    METRIC_METADATA_TYPE,
+                                    Type::Enum,
+                                    Some("MetricMetadata.MetricType"),
+                                    false,
+                                ),
+                                descriptor_field(
+                                    "metric_family_name",
+                                    METRIC_METADATA_FAMILY_NAME,
+                                    Type::String,
+                                    None,
+                                    false,
+                                ),
+                                descriptor_field(
+                                    "help",
+                                    METRIC_METADATA_HELP,
+                                    Type::String,
+                                    None,
+                                    false,
+                                ),
+                                descriptor_field(
+                                    "unit",
+                                    METRIC_METADATA_UNIT,
+                                    Type::String,
+                                    None,
+                                    false,
+                                ),
+                            ],
+                        )
+                    },
+                ],
+                ..Default::default()
+            }],
+        }
+    }
+
+    #[test]
+    fn test_proto_descriptor_is_up_to_date() {
+        use prost::Message;
+
+        let expected = remote_write_descriptor_set();
+        if std::env::var_os("UPDATE_PROTO_DESCRIPTOR").is_some() {
+            let path = concat!(env!("CARGO_MANIFEST_DIR"), "/src/remote_write.desc");
+            std::fs::write(path, expected.encode_to_vec()).unwrap();
+            return;
+        }
+
+        let decoded =
+            prost_types::FileDescriptorSet::decode(WriteRequest::PROTO_DESCRIPTOR).unwrap();
+        assert_eq!(decoded, expected);
+    }
+
+    fn read_varint(buf: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = buf[0];
+            *buf = &buf[1..];
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        value
+    }
+
+    /// Decode a message generically, using only the descriptor, into
+    /// `path=value` lines.
+    fn dump_with_descriptor(
+        file: &prost_types::FileDescriptorProto,
+        type_name: &str,
+        mut buf: &[u8],
+        prefix: &str,
+        out: &mut Vec<String>,
+    ) {
+        use prost_types::field_descriptor_proto::Type;
+
+        let message = file
+            .message_type
+            .iter()
+            .find(|m| m.name() == type_name)
+            .unwrap();
+
+        while !buf.is_empty() {
+            let key = read_varint(&mut buf);
+            let field = message
+                .field
+                .iter()
+                .find(|f| f.number() as u64 == key >> 3)
+                .unwrap();
+            let path = format!("{prefix}{}", field.name());
+
+            match field.r#type() {
+                Type::Double => {
+                    let (bytes, rest) = buf.split_at(8);
+                    buf = rest;
+                    let value = f64::from_le_bytes(bytes.try_into().unwrap());
+                    out.push(format!("{path}={value}"));
+                }
+                Type::Int64 => {
+                    let value = read_varint(&mut buf) as i64;
+                    out.push(format!("{path}={value}"));
+                }
+                Type::Enum => {
+                    let number = read_varint(&mut buf) as i32;
+                    let value = message
+                        .enum_type
+                        .iter()
+                        .flat_map(|e| &e.value)
+                        .find(|v| v.number() == number)
+                        .unwrap();
+                    out.push(format!("{path}={}", value.name()));
+                }
+                Type::String | Type::Message => {
+                    let len = read_varint(&mut buf) as usize;
+                    let (bytes, rest) = buf.split_at(len);
+                    buf = rest;
+                    if field.r#type() == Type::String {
+                        let value = std::str::from_utf8(bytes).unwrap();
+                        out.push(format!("{path}={value:?}"));
+                    } else {
+                        let nested = field.type_name().trim_start_matches(".prometheus.");
+                        dump_with_descriptor(file, nested, bytes, &format!("{path}."), out);
+                    }
+                }
+                other => panic!("unexpected field type {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_with_proto_descriptor() {
+        use prost::Message;
+
+        let set = prost_types::FileDescriptorSet::decode(WriteRequest::PROTO_DESCRIPTOR).unwrap();
+        let file = &set.file[0];
+        assert_eq!(file.package(), "prometheus");
+
+        let req = WriteRequest {
+            timeseries: vec![TimeSeries {
+                labels: vec![Label {
+                    name: LABEL_NAME.to_string(),
+                    value: "requests".to_string(),
+                }],
+                samples: vec![Sample {
+                    value: 1.5,
+                    timestamp: 1000,
+                }],
+                exemplars: vec![Exemplar {
+                    labels: vec![Label {
+                        name: "trace_id".to_string(),
+                        value: "abc".to_string(),
+                    }],
+                    value: 0.5,
+                    timestamp: 999,
+                }],
+            }],
+            metadata: vec![MetricMetadata {
+                r#type: MetricMetadataType::Counter as i32,
+                metric_family_name: "requests".to_string(),
+                help: "Total requests.".to_string(),
+                unit: "".to_string(),
+            }],
+        };
+
+        let mut lines = Vec::new();
+        dump_with_descriptor(file, "WriteRequest", &req.encode_to_vec(), "", &mut lines);
+        assert_eq!(
+            lines,
+            vec![
+                r#"timeseries.labels.name="__name__""#,
+                r#"timeseries.labels.value="requests""#,
+                "timeseries.samples.value=1.5",
+                "timeseries.samples.timestamp=1000",
+                r#"timeseries.exemplars.labels.name="trace_id""#,
+                r#"timeseries.exemplars.labels.value="abc""#,
+                "timeseries.exemplars.value=0.5",
+                "timeseries.exemplars.timestamp=999",
+                "metadata.type=COUNTER",
+                r#"metadata.metric_family_name="requests""#,
+                r#"metadata.help="Total requests.""#,
+            ]
+        );
+    }
+}
+
+#[cfg(feature = "prometheus")]
+#[cfg(test)]
+mod prometheus_tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn reset_timestamps(series: &mut [TimeSeries]) {
+        for s in series {
+            for sample in &mut s.samples {
+                sample.timestamp = 0;
+            }
+        }
+    }
+
+    fn label(name: &str, value: &str) -> Label {
+        Label {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_from_metric_family_counter() {
+        let registry = prometheus::Registry::new();
+        let counter = prometheus::Counter::new("requests_total", "Requests").unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        counter.inc_by(3.0);
+
+        let families = registry.gather();
+        assert_eq!(families.len(), 1);
+        let mut series = TimeSeries::from_metric_family(&families[0]);
+        reset_timestamps(&mut series);
+
+        assert_eq!(
+            series,
+            vec![TimeSeries {
+                labels: vec![label(LABEL_NAME, "requests_total")],
+                samples: vec![Sample {
+                    value: 3.0,
+                    timestamp: 0
+                }],
+                exemplars: vec![],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_from_metric_family_gauge_with_labels() {
+        let registry = prometheus::Registry::new();
+        let gauge = prometheus::GaugeVec::new(
+            prometheus::Opts::new("temperature", "Temperature"),
+            &["room"],
+        )
+        .unwrap();
+        registry.register(Box::new(gauge.clone())).unwrap();
+        gauge.with_label_values(&["kitchen"]).set(21.5);
+        gauge.with_label_values(&["office"]).set(19.0);
+
+        let families = registry.gather();
+        let mut series = TimeSeries::from_metric_family(&families[0]);
+        reset_timestamps(&mut series);
+
+        assert_eq!(
+            series,
+            vec![
+                TimeSeries {
+                    labels: vec![label(LABEL_NAME, "temperature"), label("room", "kitchen")],
+                    samples: vec![Sample {
+                        value: 21.5,
+                        timestamp: 0
+                    }],
+                    exemplars: vec![],
+                },
+                TimeSeries {
+                    labels: vec![label(LABEL_NAME, "temperature"), label("room", "office")],
+                    samples: vec![Sample {
+                        value: 19.0,
+                        timestamp: 0
+                    }],
+                    exemplars: vec![],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_from_metric_family_histogram_le_values_intact() {
+        let registry = prometheus::Registry::new();
+        let histogram = prometheus::Histogram::with_opts(
+            prometheus::HistogramOpts::new("latency", "Latency")
+                .buckets(vec![0.00001, 0.1, 1.0, 10.0]),
+        )
+        .unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+        histogram.observe(0.5);
+        histogram.observe(5.0);
+        histogram.observe(50.0);
+
+        let families = registry.gather();
+        let mut req = WriteRequest {
+            timeseries: TimeSeries::from_metric_family(&families[0]),
+            metadata: vec![],
+        };
+        req.timeseries.retain(|s| {
+            s.labels
+                .iter()
+                .any(|l| l.name == LABEL_NAME && l.value == "latency_bucket")
+        });
+        reset_timestamps(&mut req.timeseries);
+
+        assert_eq!(
+            req.to_text_format(),
+            r#"latency_bucket{le="1e-5"} 0 0
+latency_bucket{le="0.1"} 0 0
+latency_bucket{le="1"} 1 0
+latency_bucket{le="10"} 2 0
+latency_bucket{le="+Inf"} 3 0
+"#
+        );
+    }
+
+    #[test]
+    fn test_from_metric_family_histogram() {
+        let registry = prometheus::Registry::new();
+        let histogram = prometheus::Histogram::with_opts(
+            prometheus::HistogramOpts::new("latency", "Latency").buckets(vec![0.1, 1.0]),
+        )
+        .unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+        histogram.observe(0.5);
+
+        let families = registry.gather();
+        let series = TimeSeries::from_metric_family(&families[0]);
+
+        let names = series
+            .iter()
+            .map(|s| {
+                s.labels
+                    .iter()
+                    .map(|l| format!("{}={}", l.name, l.value))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "__name__=latency_bucket,le=0.1",
+                "__name__=latency_bucket,le=1",
+                "__name__=latency_bucket,le=+Inf",
+                "__name__=latency_sum",
+                "__name__=latency_count",
+            ]
+        );
+    }
+}
+
+#[cfg(feature = "otlp")]
+#[cfg(test)]
+mod otlp_tests {
+    use super::*;
+    use otlp::{
+        any_value, metric, number_data_point, AnyValue, Gauge, Histogram, HistogramDataPoint,
+        KeyValue, Metric, MetricsData, NumberDataPoint, Resource, ResourceMetrics, ScopeMetrics,
+        Sum,
+    };
+
+    fn attr(key: &str, value: &str) -> KeyValue {
+        KeyValue {
+            key: key.to_string(),
+            value: Some(AnyValue {
+                value: Some(any_value::Value::StringValue(value.to_string())),
+            }),
+        }
+    }
+
+    fn metrics_data(resource: Vec<KeyValue>, metrics: Vec<Metric>) -> MetricsData {
+        MetricsData {
+            resource_metrics: vec![ResourceMetrics {
+                resource: Some(Resource {
+                    attributes: resource,
+                }),
+                scope_metrics: vec![ScopeMetrics { metrics }],
+            }],
+        }
+    }
+
+    #[test]
+    fn test_from_otlp_gauge() {
+        let data = metrics_data(
+            vec![attr("service.name", "api"), attr("env", "prod")],
+            vec![Metric {
+                name: "queue.size".to_string(),
+                description: "Items in the queue".to_string(),
+                unit: "1".to_string(),
+                data: Some(metric::Data::Gauge(Gauge {
+                    data_points: vec![
+                        NumberDataPoint {
+                            attributes: vec![attr("queue", "a"), attr("env", "dev")],
+                            time_unix_nano: 1_000_000_000,
+                            value: Some(number_data_point::Value::AsDouble(2.5)),
+                            ..Default::default()
+                        },
+                        NumberDataPoint {
+                            attributes: vec![attr("queue", "b")],
+                            time_unix_nano: 2_000_000_000,
+                            value: Some(number_data_point::Value::AsInt(7)),
+                            ..Default::default()
+                        },
+                    ],
+                })),
+            }],
+        );
+
+        let req = WriteRequest::from_otlp(&data).unwrap();
+        assert_eq!(
+            req.timeseries,
+            vec![
+                TimeSeries {
+                    labels: vec![
+                        Label {
                             name: LABEL_NAME.to_string(),
-                            value: "mygauge".to_string()
-                        },],
-                        samples: vec![Sample {
-                            value: 100.0,
-                            timestamp: 100,
+                            value: "queue_size".to_string(),
+                        },
+                        Label {
+                            name: "env".to_string(),
+                            value: "dev".to_string(),
+                        },
+                        Label {
+                            name: "queue".to_string(),
+                            value: "a".to_string(),
+                        },
+                        Label {
+                            name: "service_name".to_string(),
+                            value: "api".to_string(),
+                        },
+                    ],
+                    samples: vec![Sample {
+                        value: 2.5,
+                        timestamp: 1000,
+                    }],
+                    ..Default::default()
+                },
+                TimeSeries {
+                    labels: vec![
+                        Label {
+                            name: LABEL_NAME.to_string(),
+                            value: "queue_size".to_string(),
+                        },
+                        Label {
+                            name: "env".to_string(),
+                            value: "prod".to_string(),
+                        },
+                        Label {
+                            name: "queue".to_string(),
+                            value: "b".to_string(),
+                        },
+                        Label {
+                            name: "service_name".to_string(),
+                            value: "api".to_string(),
+                        },
+                    ],
+                    samples: vec![Sample {
+                        value: 7.0,
+                        timestamp: 2000,
+                    }],
+                    ..Default::default()
+                },
+            ]
+        );
+        assert_eq!(req.metadata.len(), 1);
+        assert_eq!(req.metadata[0].metric_family_name, "queue_size");
+        assert_eq!(req.metadata[0].r#type(), MetricMetadataType::Gauge);
+        assert_eq!(req.metadata[0].help, "Items in the queue");
+        assert_eq!(req.metadata[0].unit, "1");
+    }
+
+    #[test]
+    fn test_from_otlp_sum_and_histogram() {
+        let data = metrics_data(
+            vec![],
+            vec![
+                Metric {
+                    name: "requests".to_string(),
+                    data: Some(metric::Data::Sum(Sum {
+                        data_points: vec![NumberDataPoint {
+                            time_unix_nano: 1_000_000_000,
+                            value: Some(number_data_point::Value::AsInt(3)),
+                            ..Default::default()
                         }],
-                    },
-                ]
+                        aggregation_temporality: 2,
+                        is_monotonic: true,
+                    })),
+                    ..Default::default()
+                },
+                Metric {
+                    name: "latency".to_string(),
+                    data: Some(metric::Data::Histogram(Histogram {
+                        data_points: vec![HistogramDataPoint {
+                            time_unix_nano: 1_000_000_000,
+                            count: 5,
+                            sum: Some(1.5),
+                            bucket_counts: vec![2, 3],
+                            explicit_bounds: vec![0.5],
+                            ..Default::default()
+                        }],
+                        aggregation_temporality: 2,
+                    })),
+                    ..Default::default()
+                },
+            ],
+        );
+
+        let req = WriteRequest::from_otlp(&data).unwrap();
+        let samples = req
+            .iter_samples()
+            .map(|(labels, sample)| {
+                let labels = labels
+                    .iter()
+                    .map(|l| format!("{}={}", l.name, l.value))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                (labels, sample.value)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            samples,
+            vec![
+                ("__name__=latency_bucket,le=0.5".to_string(), 2.0),
+                ("__name__=latency_bucket,le=+Inf".to_string(), 5.0),
+                ("__name__=latency_count".to_string(), 5.0),
+                ("__name__=latency_sum".to_string(), 1.5),
+                ("__name__=requests".to_string(), 3.0),
+            ]
+        );
+        let types = req
+            .metadata
+            .iter()
+            .map(|m| (m.metric_family_name.as_str(), m.r#type()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            types,
+            vec![
+                ("latency", MetricMetadataType::Histogram),
+                ("requests", MetricMetadataType::Counter),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_from_otlp_delta_sum_is_error() {
+        let data = metrics_data(
+            vec![],
+            vec![Metric {
+                name: "requests".to_string(),
+                data: Some(metric::Data::Sum(Sum {
+                    data_points: vec![],
+                    aggregation_temporality: 1,
+                    is_monotonic: true,
+                })),
+                ..Default::default()
+            }],
+        );
+        let err = WriteRequest::from_otlp(&data).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "metric 'requests': sums with delta temporality are not supported"
+        );
+    }
+
+    #[test]
+    fn test_from_otlp_label_collisions() {
+        let data = metrics_data(
+            vec![attr("a.b", "resource"), attr("c", "resource")],
+            vec![
+                Metric {
+                    name: "up".to_string(),
+                    data: Some(metric::Data::Gauge(Gauge {
+                        data_points: vec![NumberDataPoint {
+                            attributes: vec![
+                                attr("a_b", "2"),
+                                attr("a.b", "1"),
+                                attr("__name__", "other"),
+                                attr("c", "point"),
+                            ],
+                            time_unix_nano: 1_000_000_000,
+                            value: Some(number_data_point::Value::AsDouble(1.0)),
+                            ..Default::default()
+                        }],
+                    })),
+                    ..Default::default()
+                },
+                Metric {
+                    name: "latency".to_string(),
+                    data: Some(metric::Data::Histogram(Histogram {
+                        data_points: vec![HistogramDataPoint {
+                            attributes: vec![attr("le", "x")],
+                            time_unix_nano: 1_000_000_000,
+                            count: 1,
+                            bucket_counts: vec![1],
+                            ..Default::default()
+                        }],
+                        aggregation_temporality: 2,
+                    })),
+                    ..Default::default()
+                },
+            ],
+        );
+
+        let req = WriteRequest::from_otlp(&data).unwrap();
+        req.validate().unwrap();
+        let series = req
+            .timeseries
+            .iter()
+            .map(|series| {
+                series
+                    .labels
+                    .iter()
+                    .map(|l| format!("{}={}", l.name, l.value))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            series,
+            vec![
+                "__name__=latency_bucket,a_b=resource,c=resource,le=+Inf",
+                "__name__=latency_count,a_b=resource,c=resource",
+                "__name__=up,a_b=1;2,c=point",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_from_otlp_decode() {
+        // Encoded with the opentelemetry-proto crate. Contains fields that
+        // are not part of the messages of this crate, like the instrumentation
+        // scope, schema urls, start times and an array attribute.
+        let hex = "0ac0020a280a150a0c736572766963652e6e616d6512050a036170690a0f0a047461677312072a050a030a017812ea010a060a0474657374125d0a0a71756575652e73697a6512124974656d7320696e207468652071756575651a01312a380a36110065cd1d000000001900ca9a3b000000002100000000000004403a0c0a05717565756512030a01613a0b0a0573686172641202180312240a0872657175657374733a180a121900ca9a3b0000000031070000000000000010021801125b0a076c6174656e63791a01734a4d0a491900ca9a3b0000000021050000000000000029000000000000f83f3210020000000000000003000000000000003a08000000000000e03f599a9999999999b93f61cdccccccccccec3f10021a2768747470733a2f2f6f70656e74656c656d657472792e696f2f736368656d61732f312e32312e30";
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect::<Vec<_>>();
+        let data = <MetricsData as prost::Message>::decode(bytes.as_slice()).unwrap();
+
+        let req = WriteRequest::from_otlp(&data).unwrap();
+        assert_eq!(
+            req.to_text_format(),
+            "latency_bucket{le=\"0.5\",service_name=\"api\"} 2 1000\n\
+             latency_bucket{le=\"+Inf\",service_name=\"api\"} 5 1000\n\
+             latency_count{service_name=\"api\"} 5 1000\n\
+             latency_sum{service_name=\"api\"} 1.5 1000\n\
+             queue_size{queue=\"a\",service_name=\"api\",shard=\"3\"} 2.5 1000\n\
+             requests{service_name=\"api\"} 7 1000\n"
+        );
+        let metadata = req
+            .metadata
+            .iter()
+            .map(|m| {
+                (
+                    m.metric_family_name.as_str(),
+                    m.r#type(),
+                    m.help.as_str(),
+                    m.unit.as_str(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            metadata,
+            vec![
+                ("latency", MetricMetadataType::Histogram, "", "s"),
+                (
+                    "queue_size",
+                    MetricMetadataType::Gauge,
+                    "Items in the queue",
+                    "1"
+                ),
+                ("requests", MetricMetadataType::Counter, "", ""),
+            ]
+        );
+    }
+}
+
+#[cfg(feature = "metrics")]
+#[cfg(test)]
+mod metrics_tests {
+    use super::*;
+
+    #[test]
+    fn test_from_metrics_snapshot_counter_and_gauge() {
+        let recorder = metrics_util::debugging::DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        metrics::with_local_recorder(&recorder, || {
+            metrics::counter!("http.requests", "method" => "GET").increment(3);
+            metrics::gauge!("queue_size").set(7.5);
+        });
+
+        let req = WriteRequest::from_metrics_snapshot(snapshotter.snapshot(), 1000);
+        assert_eq!(
+            req.timeseries,
+            vec![
+                TimeSeries {
+                    labels: vec![
+                        Label {
+                            name: LABEL_NAME.to_string(),
+                            value: "http_requests".to_string(),
+                        },
+                        Label {
+                            name: "method".to_string(),
+                            value: "GET".to_string(),
+                        },
+                    ],
+                    samples: vec![Sample {
+                        value: 3.0,
+                        timestamp: 1000,
+                    }],
+                    exemplars: vec![],
+                },
+                TimeSeries {
+                    labels: vec![Label {
+                        name: LABEL_NAME.to_string(),
+                        value: "queue_size".to_string(),
+                    }],
+                    samples: vec![Sample {
+                        value: 7.5,
+                        timestamp: 1000,
+                    }],
+                    exemplars: vec![],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_from_metrics_snapshot_histogram() {
+        let recorder = metrics_util::debugging::DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        metrics::with_local_recorder(&recorder, || {
+            let histogram = metrics::histogram!("latency");
+            histogram.record(0.2);
+            histogram.record(3.0);
+        });
+
+        let req = WriteRequest::from_metrics_snapshot(snapshotter.snapshot(), 1000);
+        // `Option::is_none_or` needs Rust 1.82.
+        #[allow(clippy::unnecessary_map_or)]
+        let value = |name: &str, le: Option<&str>| {
+            req.timeseries
+                .iter()
+                .find(|s| {
+                    s.labels
+                        .iter()
+                        .any(|l| l.name == LABEL_NAME && l.value == name)
+                        && le.map_or(true, |le| {
+                            s.labels.iter().any(|l| l.name == "le" && l.value == le)
+                        })
+                })
+                .map(|s| s.samples[0].value)
+                .unwrap()
+        };
+        assert_eq!(value("latency_bucket", Some("0.1")), 0.0);
+        assert_eq!(value("latency_bucket", Some("0.25")), 1.0);
+        assert_eq!(value("latency_bucket", Some("5")), 2.0);
+        assert_eq!(value("latency_bucket", Some("+Inf")), 2.0);
+        assert_eq!(value("latency_sum", None), 3.2);
+        assert_eq!(value("latency_count", None), 2.0);
+        assert_eq!(req.timeseries.len(), DEFAULT_HISTOGRAM_BUCKETS.len() + 3);
+    }
+
+    #[test]
+    fn test_from_metrics_snapshot_sanitizes_like_legacy() {
+        let recorder = metrics_util::debugging::DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        metrics::with_local_recorder(&recorder, || {
+            metrics::gauge!("5xx.rate:total").set(1.0);
+        });
+
+        let req = WriteRequest::from_metrics_snapshot(snapshotter.snapshot(), 1000);
+        let mut legacy =
+            WriteRequest::from_samples(vec![("5xx.rate:total".to_string(), vec![], 1.0, 1000)]);
+        legacy.sanitize_names_legacy();
+        assert_eq!(req, legacy);
+        assert_eq!(req.timeseries[0].labels[0].value, "_5xx_rate:total");
+    }
+}
+
+#[cfg(feature = "ureq")]
+#[cfg(test)]
+mod ureq_tests {
+    use std::io::{Read, Write};
+
+    use super::*;
+
+    /// Start a server that answers a single request with `response`.
+    ///
+    /// Returns the endpoint URL and a receiver for the raw request.
+    fn serve_once(response: &'static str) -> (url::Url, std::sync::mpsc::Receiver<Vec<u8>>) {
+        serve_sequence(vec![response])
+    }
+
+    /// Answer one connection with each of `responses`, in order.
+    fn serve_sequence(
+        responses: Vec<&'static str>,
+    ) -> (url::Url, std::sync::mpsc::Receiver<Vec<u8>>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/v1/write", listener.local_addr().unwrap());
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut buf = Vec::new();
+                let mut chunk = [0u8; 4096];
+                // Read until the declared body has arrived.
+                loop {
+                    let n = stream.read(&mut chunk).unwrap();
+                    buf.extend_from_slice(&chunk[..n]);
+                    let head = String::from_utf8_lossy(&buf).to_ascii_lowercase();
+                    if let Some(end) = head.find("\r\n\r\n") {
+                        let len = head
+                            .lines()
+                            .find_map(|line| line.strip_prefix("content-length:"))
+                            .map(|v| v.trim().parse::<usize>().unwrap())
+                            .unwrap_or(0);
+                        if n == 0 || buf.len() >= end + 4 + len {
+                            break;
+                        }
+                    }
+                }
+                stream.write_all(response.as_bytes()).unwrap();
+                tx.send(buf).unwrap();
+            }
+        });
+        (url::Url::parse(&url).unwrap(), rx)
+    }
+
+    #[test]
+    fn test_send_batches() {
+        let stats = || SendStats {
+            series: 1,
+            samples: 1,
+            compressed_bytes: 1,
+            status: 204,
+            elapsed: std::time::Duration::ZERO,
+        };
+        let send = |attempts: &mut Vec<usize>, index: usize, _: &&str| {
+            attempts.push(index);
+            if index == 1 {
+                Err(SendError::new("failed").with_source("cause"))
+            } else {
+                Ok(stats())
             }
+        };
+
+        let mut attempts = Vec::new();
+        let err = send_batches(&["a", "b", "c"], SendPolicy::FailFast, |index, batch| {
+            send(&mut attempts, index, batch)
+        })
+        .unwrap_err();
+        assert_eq!(attempts, vec![0, 1]);
+        assert_eq!(
+            err.to_string(),
+            "1 of 3 batches failed, 1 not sent; batch 2: failed: cause"
         );
 
-        let _x = req.clone().encode_proto3();
-        let _y = req.encode_compressed();
+        let mut attempts = Vec::new();
+        let err = send_batches(
+            &["a", "b", "c"],
+            SendPolicy::ContinueOnError,
+            |index, batch| send(&mut attempts, index, batch),
+        )
+        .unwrap_err();
+        assert_eq!(attempts, vec![0, 1, 2]);
+        assert_eq!(err.sent.len(), 2);
+        assert_eq!(
+            err.to_string(),
+            "1 of 3 batches failed; batch 2: failed: cause"
+        );
+    }
+
+    #[test]
+    fn test_send_batches_ureq() {
+        const OK: &str =
+            "HTTP/1.1 204 No Content\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+        const BAD: &str =
+            "HTTP/1.1 400 Bad Request\r\ncontent-length: 3\r\nconnection: close\r\n\r\nbad";
+        let batches = ["a", "b", "c"]
+            .map(|name| WriteRequest::from_samples(vec![(name.to_string(), vec![], 1.0, 1)]));
+        let agent = ureq::AgentBuilder::new().build();
+
+        let (url, rx) = serve_sequence(vec![OK, BAD, OK]);
+        let err =
+            WriteRequest::send_batches_ureq(&batches, &agent, &url, "test", SendPolicy::FailFast)
+                .unwrap_err();
+        assert_eq!(err.sent.len(), 1);
+        assert_eq!(err.failures.len(), 1);
+        assert_eq!(err.failures[0].0, 1);
+        assert_eq!(err.failures[0].1.status(), Some(400));
+        assert_eq!(err.skipped, 1);
+        assert!(
+            err.to_string()
+                .starts_with("1 of 3 batches failed, 1 not sent; batch 2: "),
+            "{err}"
+        );
+        for _ in 0..2 {
+            rx.recv().unwrap();
+        }
+
+        let (url, rx) = serve_sequence(vec![OK, BAD, OK]);
+        let err = WriteRequest::send_batches_ureq(
+            &batches,
+            &agent,
+            &url,
+            "test",
+            SendPolicy::ContinueOnError,
+        )
+        .unwrap_err();
+        assert_eq!(err.sent.len(), 2);
+        assert_eq!(err.failures.len(), 1);
+        assert_eq!(err.failures[0].0, 1);
+        assert_eq!(err.skipped, 0);
+        assert!(
+            err.to_string()
+                .starts_with("1 of 3 batches failed; batch 2: "),
+            "{err}"
+        );
+        for _ in 0..3 {
+            rx.recv().unwrap();
+        }
+
+        let (url, _rx) = serve_sequence(vec![OK, OK, OK]);
+        let stats =
+            WriteRequest::send_batches_ureq(&batches, &agent, &url, "test", SendPolicy::FailFast)
+                .unwrap();
+        assert_eq!(stats.len(), 3);
+    }
+
+    #[test]
+    fn test_send_ureq() {
+        let (url, rx) =
+            serve_once("HTTP/1.1 204 No Content\r\ncontent-length: 0\r\nconnection: close\r\n\r\n");
+        let req = WriteRequest::from_samples(vec![
+            ("x".to_string(), vec![], 1.0, 1),
+            ("x".to_string(), vec![], 2.0, 2),
+            ("y".to_string(), vec![], 3.0, 1),
+        ]);
+        let agent = ureq::AgentBuilder::new().build();
+
+        let stats = req.send_ureq(&agent, &url, "test").unwrap();
+        assert_eq!(stats.series, 2);
+        assert_eq!(stats.samples, 3);
+        assert_eq!(stats.status, 204);
+
+        let raw = rx.recv().unwrap();
+        let head_end = raw.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let head = String::from_utf8_lossy(&raw[..head_end]).to_ascii_lowercase();
+        assert!(head.starts_with("post /api/v1/write"), "{head}");
+        assert!(head.contains("content-encoding: snappy"), "{head}");
+        assert!(head.contains("user-agent: test"), "{head}");
+        assert_eq!(stats.compressed_bytes, raw.len() - head_end);
+
+        let body = snap::raw::Decoder::new()
+            .decompress_vec(&raw[head_end..])
+            .unwrap();
+        assert_eq!(
+            WriteRequest::try_from(body.as_slice()).unwrap(),
+            req.sorted()
+        );
+    }
+
+    #[test]
+    fn test_send_ureq_error_status() {
+        let (url, _rx) = serve_once(
+            "HTTP/1.1 429 Too Many Requests\r\nretry-after: 7\r\ncontent-length: 9\r\nconnection: close\r\n\r\nslow down",
+        );
+        let req = WriteRequest::from_samples(vec![("x".to_string(), vec![], 1.0, 1)]);
+        let agent = ureq::AgentBuilder::new().build();
+
+        let err = req.send_ureq(&agent, &url, "test").unwrap_err();
+        assert_eq!(err.status(), Some(429));
+        assert!(err.is_retryable());
+        assert_eq!(err.retry_after(), Some(std::time::Duration::from_secs(7)));
+        assert_eq!(
+            err.to_string(),
+            "server returned error status code 429: slow down"
+        );
+    }
+
+    #[test]
+    fn test_parse_retry_after_seconds() {
+        let now = std::time::SystemTime::now();
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(std::time::Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after(" 0 ", now),
+            Some(std::time::Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn test_parse_retry_after_http_date() {
+        let now = httpdate::parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT").unwrap();
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+            Some(std::time::Duration::from_secs(30))
+        );
+        // Dates in the past mean no delay.
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now),
+            Some(std::time::Duration::ZERO)
+        );
     }
 }